@@ -0,0 +1,239 @@
+use std::convert::TryFrom;
+
+use serde::{Deserialize, Serialize};
+
+use crate::camera::CameraSettings;
+use crate::errors::*;
+use crate::vec3::Vec3;
+
+// The camera at a single frame of an animation. Values that are not given are taken from the
+// scene's (or the command line's) camera.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CameraKeyframe {
+    pub frame: f32,
+    pub look_from: Vec3,
+    pub look_at: Vec3,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vfov: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focus_distance: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aperture: Option<f32>,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum Interpolation {
+    Linear,
+    // Passes through every keyframe with a continuous velocity.
+    CatmullRom,
+}
+
+fn default_interpolation() -> Interpolation {
+    Interpolation::Linear
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "CameraAnimationFields")]
+pub struct CameraAnimation {
+    pub interpolation: Interpolation,
+    pub keyframes: Vec<CameraKeyframe>,
+}
+
+// A CameraAnimation as written in a scene file, whose keyframes are checked when it is loaded.
+#[derive(Deserialize)]
+struct CameraAnimationFields {
+    #[serde(default = "default_interpolation")]
+    interpolation: Interpolation,
+    keyframes: Vec<CameraKeyframe>,
+}
+
+impl TryFrom<CameraAnimationFields> for CameraAnimation {
+    type Error = Error;
+
+    fn try_from(fields: CameraAnimationFields) -> Result<CameraAnimation> {
+        for keyframe in &fields.keyframes {
+            if !keyframe.frame.is_finite() {
+                return Err(ErrorKind::InvalidParam(
+                    keyframe.frame,
+                    "keyframe frame must be a finite number".into(),
+                )
+                .into());
+            }
+        }
+        Ok(CameraAnimation {
+            interpolation: fields.interpolation,
+            keyframes: fields.keyframes,
+        })
+    }
+}
+
+// The interpolated quantities of a keyframe, with defaults filled in.
+#[derive(Clone, Copy)]
+struct Key {
+    look_from: Vec3,
+    look_at: Vec3,
+    vfov: f32,
+    focus_distance: f32,
+    aperture: f32,
+}
+
+impl Key {
+    fn combine(weights: [f32; 4], keys: [&Key; 4]) -> Key {
+        let vec = |f: fn(&Key) -> Vec3| {
+            keys.iter()
+                .zip(weights.iter())
+                .fold(Vec3::origin(), |sum, (key, weight)| sum + *weight * f(key))
+        };
+        let scalar = |f: fn(&Key) -> f32| {
+            keys.iter()
+                .zip(weights.iter())
+                .map(|(key, weight)| weight * f(key))
+                .sum::<f32>()
+        };
+        Key {
+            look_from: vec(|k| k.look_from),
+            look_at: vec(|k| k.look_at),
+            vfov: scalar(|k| k.vfov),
+            focus_distance: scalar(|k| k.focus_distance),
+            aperture: scalar(|k| k.aperture),
+        }
+    }
+}
+
+impl CameraAnimation {
+    // The camera at `frame`, which may fall between keyframes. Frames before the first
+    // keyframe or after the last one hold that keyframe's camera.
+    pub fn settings_at(&self, base: &CameraSettings, frame: f32) -> Result<CameraSettings> {
+        if self.keyframes.is_empty() {
+            return Err(ErrorKind::MissingParam("keyframes".to_string()).into());
+        }
+
+        let mut keyframes = self.keyframes.iter().collect::<Vec<_>>();
+        keyframes.sort_by(|a, b| a.frame.total_cmp(&b.frame));
+        let keys = keyframes
+            .iter()
+            .map(|keyframe| Key {
+                look_from: keyframe.look_from,
+                look_at: keyframe.look_at,
+                vfov: keyframe.vfov.unwrap_or(base.vfov),
+                focus_distance: keyframe.focus_distance.unwrap_or_else(|| {
+                    base.focus_distance
+                        .unwrap_or_else(|| (keyframe.look_from - keyframe.look_at).length())
+                }),
+                aperture: keyframe.aperture.unwrap_or(base.aperture),
+            })
+            .collect::<Vec<_>>();
+
+        // The segment [i, i + 1] containing frame.
+        let last = keys.len() - 1;
+        let i = keyframes
+            .iter()
+            .rposition(|keyframe| keyframe.frame <= frame)
+            .unwrap_or(0);
+        let key = if frame <= keyframes[0].frame {
+            keys[0]
+        } else if i == last {
+            keys[last]
+        } else {
+            let t = (frame - keyframes[i].frame) / (keyframes[i + 1].frame - keyframes[i].frame);
+            let neighbors = [
+                &keys[i.saturating_sub(1)],
+                &keys[i],
+                &keys[i + 1],
+                &keys[usize::min(i + 2, last)],
+            ];
+            Key::combine(self.weights(t), neighbors)
+        };
+
+        let mut settings = base.clone();
+        settings.look_from = key.look_from;
+        settings.look_at = key.look_at;
+        settings.vfov = key.vfov;
+        settings.focus_distance = Some(key.focus_distance);
+        settings.aperture = key.aperture;
+        Ok(settings)
+    }
+
+    // Weights of the four keys around a segment, at t in [0, 1] along it.
+    fn weights(&self, t: f32) -> [f32; 4] {
+        match self.interpolation {
+            Interpolation::Linear => [0.0, 1.0 - t, t, 0.0],
+            Interpolation::CatmullRom => {
+                let t2 = t * t;
+                let t3 = t2 * t;
+                [
+                    0.5 * (-t3 + 2.0 * t2 - t),
+                    0.5 * (3.0 * t3 - 5.0 * t2 + 2.0),
+                    0.5 * (-3.0 * t3 + 4.0 * t2 + t),
+                    0.5 * (t3 - t2),
+                ]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::camera::Projection;
+
+    fn keyframe(frame: f32, x: f32) -> CameraKeyframe {
+        CameraKeyframe {
+            frame,
+            look_from: Vec3::cartesian(x, 0.0, 0.0),
+            look_at: Vec3::cartesian(x, 0.0, -1.0),
+            vfov: None,
+            focus_distance: None,
+            aperture: None,
+        }
+    }
+
+    #[test]
+    fn test_interpolation() {
+        let base = CameraSettings {
+            projection: Projection::Perspective,
+            look_from: Vec3::origin(),
+            look_at: Vec3::cartesian(0.0, 0.0, -1.0),
+            look_up: Vec3::cartesian(0.0, 1.0, 0.0),
+            vfov: 60.0,
+            view_width: 2.0,
+            fisheye_fov: 180.0,
+            panorama_fov: 360.0,
+            stereo: None,
+            ipd: 0.064,
+            aperture: 0.0,
+            focus_distance: None,
+        };
+        let mut animation = CameraAnimation {
+            interpolation: Interpolation::Linear,
+            keyframes: vec![
+                keyframe(20.0, 4.0),
+                keyframe(0.0, 0.0),
+                keyframe(10.0, 1.0),
+                keyframe(30.0, 9.0),
+            ],
+        };
+
+        let at = |animation: &CameraAnimation, frame| {
+            animation.settings_at(&base, frame).unwrap().look_from.x()
+        };
+        assert_eq!(0.0, at(&animation, -5.0));
+        assert_eq!(0.5, at(&animation, 5.0));
+        assert_eq!(2.5, at(&animation, 15.0));
+        assert_eq!(9.0, at(&animation, 35.0));
+        assert_eq!(60.0, animation.settings_at(&base, 5.0).unwrap().vfov);
+
+        // Catmull-Rom passes through the keyframes, but curves between them.
+        animation.interpolation = Interpolation::CatmullRom;
+        assert_eq!(1.0, at(&animation, 10.0));
+        assert_eq!(4.0, at(&animation, 20.0));
+        let between = at(&animation, 15.0);
+        assert!(between > 2.0 && between < 2.5);
+
+        // Scene files with frames that are not numbers are rejected when they are loaded.
+        let animation = "keyframes: [{frame: .nan, look_from: [0, 0, 0], look_at: [0, 0, -1]}]";
+        assert!(serde_yaml::from_str::<CameraAnimation>(animation).is_err());
+        let animation = animation.replace(".nan", "3");
+        assert!(serde_yaml::from_str::<CameraAnimation>(&animation).is_ok());
+    }
+}
@@ -0,0 +1,539 @@
+use std::f32;
+
+use crate::camera::Camera;
+use crate::config::Config;
+use crate::environment::Environment;
+use crate::errors::*;
+use crate::hittest::{HitRecord, HitTest};
+use crate::integrator::{is_diffuse, light_contribution};
+use crate::light::Light;
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::stats::{count_max_depth_termination, count_path, count_ray, RayKind};
+use crate::unit_random::unit_random;
+use crate::util::random_cosine_direction;
+use crate::vec3::{dot, Vec3};
+
+// Light carried from a light path to the camera, landing at image position (u, v) (as for
+// Camera::get_ray). It belongs to whichever pixel is there, not the pixel being sampled.
+#[derive(Debug, Copy, Clone)]
+pub struct Splat {
+    pub u: f32,
+    pub v: f32,
+    pub radiance: Vec3,
+}
+
+// The light arriving at the camera along ray, found by bidirectional path tracing: a path is
+// traced from the camera and another from a randomly chosen point or spot light, and every
+// vertex of one is joined to every vertex of the other. Each way of building a path is
+// weighted by multiple importance sampling (the balance heuristic), so that every path is
+// counted once, mostly by the ways most likely to find it.
+//
+// Joining light path vertices straight to the camera (light tracing) lands on other pixels,
+// so that light is added to splats instead of being returned.
+//
+// Lights at infinity cannot start light paths, so directional lights are only found by shadow
+// rays from the camera path, and the environment only by camera paths that leave the scene.
+pub fn bidirectional(
+    ray: &Ray,
+    camera: &dyn Camera,
+    config: &Config,
+    scene: &Scene,
+    environment: &dyn Environment,
+    splats: &mut Vec<Splat>,
+) -> Result<Vec3> {
+    let max_depth = usize::from(config.max_depth);
+
+    let mut camera_path = vec![Vertex::endpoint(VertexKind::Camera, *ray.origin())];
+    let direction = ray.direction().unit_vector()?;
+    let pdf = camera
+        .importance(&direction)
+        .map_or(0.0, |importance| importance.pdf);
+    let camera_ray = Ray::new(*ray.origin(), direction);
+    let mut radiance = random_walk(
+        scene,
+        camera_ray,
+        Vec3::cartesian(1.0, 1.0, 1.0),
+        pdf,
+        max_depth,
+        &mut camera_path,
+        Some(environment),
+    )?;
+    count_path(camera_path.len() - 1);
+
+    // Lights at infinity, seen from each surface on the camera path.
+    for vertex in camera_path.iter().skip(1).filter(|vertex| !vertex.delta) {
+        let hit_record = vertex.hit.as_ref().unwrap();
+        for light in scene
+            .lights
+            .iter()
+            .filter(|light| light.position().is_none())
+        {
+            radiance =
+                radiance + vertex.beta * light_contribution(scene, hit_record, light.as_ref())?;
+        }
+    }
+
+    let lights = scene
+        .lights
+        .iter()
+        .filter(|light| light.position().is_some())
+        .collect::<Vec<_>>();
+    if lights.is_empty() {
+        return Ok(radiance);
+    }
+    let light = lights[usize::min(
+        (unit_random() * lights.len() as f32) as usize,
+        lights.len() - 1,
+    )];
+    let light_pdf = 1.0 / lights.len() as f32;
+    let mut light_path = vec![];
+    if let Some(emission) = light.emit() {
+        let mut vertex = Vertex::endpoint(VertexKind::Light(light.as_ref()), emission.origin);
+        vertex.beta = emission.intensity;
+        vertex.pdf_fwd = light_pdf;
+        light_path.push(vertex);
+        random_walk(
+            scene,
+            Ray::new(emission.origin, emission.direction),
+            emission.intensity / (light_pdf * emission.pdf),
+            emission.pdf,
+            max_depth,
+            &mut light_path,
+            None,
+        )?;
+    }
+
+    let connections = Connections {
+        camera,
+        scene,
+        camera_path: &camera_path,
+        light_path: &light_path,
+        light_pdf,
+    };
+    for t in 1..=camera_path.len() {
+        for s in 1..=light_path.len() {
+            // A point light can never be seen directly by the camera, and every strategy
+            // must make a path no longer than --max_depth surfaces.
+            if (s == 1 && t == 1) || s + t - 2 > max_depth {
+                continue;
+            }
+            if let Some(contribution) = connections.connect(s, t)? {
+                match contribution.splat {
+                    Some((u, v)) => splats.push(Splat {
+                        u,
+                        v,
+                        radiance: contribution.radiance,
+                    }),
+                    None => radiance = radiance + contribution.radiance,
+                }
+            }
+        }
+    }
+    Ok(radiance)
+}
+
+#[derive(Copy, Clone)]
+enum VertexKind<'a> {
+    Camera,
+    Light(&'a dyn Light),
+    Surface,
+}
+
+// A point on a camera or light path.
+struct Vertex<'a> {
+    kind: VertexKind<'a>,
+    point: Vec3,
+    hit: Option<HitRecord<'a>>,
+
+    // The throughput of the path up to this vertex, divided by the probability of choosing it.
+    beta: Vec3,
+
+    // Mirror and glass surfaces, which scatter in a single direction and so can never be
+    // joined to another path.
+    delta: bool,
+
+    // The probability densities, with respect to area, of choosing this vertex when tracing
+    // from the previous vertex of its own path (forward) and from the next one (reverse).
+    pdf_fwd: f32,
+    pdf_rev: f32,
+}
+
+impl<'a> Vertex<'a> {
+    fn endpoint(kind: VertexKind<'a>, point: Vec3) -> Vertex<'a> {
+        Vertex {
+            kind,
+            point,
+            hit: None,
+            beta: Vec3::cartesian(1.0, 1.0, 1.0),
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        }
+    }
+
+    fn normal(&self) -> Option<Vec3> {
+        self.hit.as_ref().map(|hit| hit.normal)
+    }
+
+    // The BSDF times the cosine term, for light leaving towards direction.
+    fn bsdf(&self, direction: &Vec3) -> Vec3 {
+        match &self.hit {
+            Some(hit) if !self.delta => hit
+                .material
+                .bsdf(hit, direction)
+                .unwrap_or_else(Vec3::origin),
+            _ => Vec3::origin(),
+        }
+    }
+
+    // Converts pdf, a density with respect to solid angle for choosing the direction from this
+    // vertex to next, into a density with respect to area at next.
+    fn convert_density(&self, pdf: f32, next: &Vertex) -> f32 {
+        let w = next.point - self.point;
+        let distance_squared = w.squared_length();
+        if distance_squared == 0.0 {
+            return 0.0;
+        }
+        match next.normal() {
+            Some(normal) => {
+                pdf * dot(&normal, &w).abs() / (distance_squared.sqrt() * distance_squared)
+            }
+            None => pdf / distance_squared,
+        }
+    }
+
+    // The density, with respect to area, of choosing next as the vertex after this one.
+    fn pdf(&self, camera: &dyn Camera, next: &Vertex) -> f32 {
+        let direction = match (next.point - self.point).unit_vector() {
+            Ok(direction) => direction,
+            Err(_) => return 0.0,
+        };
+        let pdf = match (self.kind, self.normal()) {
+            (VertexKind::Camera, _) => camera
+                .importance(&direction)
+                .map_or(0.0, |importance| importance.pdf),
+            (VertexKind::Light(light), _) => light.emission_pdf(&direction),
+            (VertexKind::Surface, Some(normal)) if !self.delta => {
+                f32::max(dot(&normal, &direction), 0.0) / f32::consts::PI
+            }
+            _ => 0.0,
+        };
+        self.convert_density(pdf, next)
+    }
+}
+
+// Extends path (which holds its starting point) from ray until it leaves the scene, is
+// absorbed, or has max_depth surfaces. beta is the throughput of the ray, and pdf the density
+// (with respect to solid angle) of its direction.
+//
+// Given an environment, returns the light from it that the path finds by leaving the scene.
+fn random_walk<'a>(
+    scene: &'a Scene,
+    ray: Ray,
+    beta: Vec3,
+    pdf: f32,
+    max_depth: usize,
+    path: &mut Vec<Vertex<'a>>,
+    environment: Option<&dyn Environment>,
+) -> Result<Vec3> {
+    let mut ray = ray;
+    let mut beta = beta;
+    let mut pdf_fwd = pdf;
+    loop {
+        count_ray(match (&path[0].kind, path.len()) {
+            (VertexKind::Camera, 1) => RayKind::Primary,
+            _ => RayKind::Secondary,
+        });
+        let hit_record = match scene.hit_test(&ray, 0.001, f32::MAX) {
+            Some(hit_record) => hit_record,
+            None => {
+                return Ok(match environment {
+                    Some(environment) => {
+                        beta * environment.radiance(&ray.direction().unit_vector()?)
+                    }
+                    None => Vec3::origin(),
+                });
+            }
+        };
+        if path.len() > max_depth {
+            count_max_depth_termination();
+            return Ok(Vec3::origin());
+        }
+
+        let previous = path.len() - 1;
+        let mut vertex = Vertex {
+            kind: VertexKind::Surface,
+            point: hit_record.point,
+            beta,
+            delta: !is_diffuse(&hit_record),
+            hit: Some(hit_record),
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+        };
+        vertex.pdf_fwd = path[previous].convert_density(pdf_fwd, &vertex);
+        let hit_record = vertex.hit.as_ref().unwrap();
+
+        // Diffuse surfaces are sampled here, rather than by the material, so that the density
+        // of every direction is known.
+        let mut pdf_rev = 0.0;
+        let scattered = if vertex.delta {
+            hit_record
+                .material
+                .scatter(&ray, hit_record)?
+                .map(|(scattered, attenuation)| {
+                    beta = beta * attenuation;
+                    pdf_fwd = 0.0;
+                    scattered
+                })
+        } else {
+            let normal = hit_record.normal;
+            let direction = random_cosine_direction(&normal);
+            let bsdf = vertex.bsdf(&direction);
+            pdf_fwd = dot(&direction, &normal) / f32::consts::PI;
+            beta = beta * (bsdf / pdf_fwd);
+            let towards_previous = (path[previous].point - vertex.point).unit_vector()?;
+            pdf_rev = f32::max(dot(&towards_previous, &normal), 0.0) / f32::consts::PI;
+            Some(Ray::new(vertex.point, direction))
+        };
+        path[previous].pdf_rev = vertex.convert_density(pdf_rev, &path[previous]);
+        path.push(vertex);
+
+        match scattered {
+            Some(scattered) if pdf_fwd > 0.0 || beta.squared_length() > 0.0 => ray = scattered,
+            _ => return Ok(Vec3::origin()),
+        }
+    }
+}
+
+// The light carried by one way of building a path, and where it lands on the image if the
+// camera path was not used.
+struct Contribution {
+    radiance: Vec3,
+    splat: Option<(f32, f32)>,
+}
+
+// The two paths traced for one sample, to be joined.
+struct Connections<'a> {
+    camera: &'a dyn Camera,
+    scene: &'a Scene,
+    camera_path: &'a [Vertex<'a>],
+    light_path: &'a [Vertex<'a>],
+
+    // The probability of having chosen the light at the start of light_path.
+    light_pdf: f32,
+}
+
+impl<'a> Connections<'a> {
+    // Joins the first s vertices of the light path to the first t of the camera path, and
+    // returns the light this carries, weighted for multiple importance sampling.
+    fn connect(&self, s: usize, t: usize) -> Result<Option<Contribution>> {
+        let qs = &self.light_path[s - 1];
+        let pt = &self.camera_path[t - 1];
+        if qs.delta || pt.delta {
+            return Ok(None);
+        }
+
+        // The light path's end is replaced by a fresh vertex when it is joined straight to the
+        // camera or straight to the light.
+        let mut sampled = None;
+        let mut splat = None;
+        let radiance = if t == 1 {
+            let to_camera = pt.point - qs.point;
+            let distance = to_camera.length();
+            let direction = (-1.0 / distance) * to_camera;
+            let importance = match self.camera.importance(&direction) {
+                Some(importance) => importance,
+                None => return Ok(None),
+            };
+            let radiance = qs.beta
+                * qs.bsdf(&(to_camera / distance))
+                * importance.importance
+                * importance.cosine
+                / (distance * distance);
+            if radiance.squared_length() == 0.0 || !self.visible(&qs.point, &pt.point)? {
+                return Ok(None);
+            }
+            splat = Some((importance.u, importance.v));
+            radiance
+        } else if s == 1 {
+            let light = match qs.kind {
+                VertexKind::Light(light) => light,
+                _ => return Ok(None),
+            };
+            let sample = match light.sample(&pt.point)? {
+                Some(sample) => sample,
+                None => return Ok(None),
+            };
+            let radiance = pt.beta * pt.bsdf(&sample.direction) * sample.radiance / self.light_pdf;
+            if radiance.squared_length() == 0.0 {
+                return Ok(None);
+            }
+            count_ray(RayKind::Shadow);
+            let shadow_ray = Ray::new(pt.point, sample.direction);
+            if self
+                .scene
+                .hit_test(&shadow_ray, 0.001, sample.distance)
+                .is_some()
+            {
+                return Ok(None);
+            }
+            let mut vertex =
+                Vertex::endpoint(qs.kind, pt.point + sample.distance * sample.direction);
+            vertex.pdf_fwd = self.light_pdf;
+            sampled = Some(vertex);
+            radiance
+        } else {
+            let w = pt.point - qs.point;
+            let distance_squared = w.squared_length();
+            if distance_squared == 0.0 {
+                return Ok(None);
+            }
+            let direction = w.unit_vector()?;
+            let radiance = qs.beta * qs.bsdf(&direction) * pt.bsdf(&(-1.0 * direction)) * pt.beta
+                / distance_squared;
+            if radiance.squared_length() == 0.0 || !self.visible(&qs.point, &pt.point)? {
+                return Ok(None);
+            }
+            radiance
+        };
+
+        let weight = self.mis_weight(s, t, sampled.as_ref());
+        Ok(Some(Contribution {
+            radiance: weight * radiance,
+            splat,
+        }))
+    }
+
+    fn visible(&self, from: &Vec3, to: &Vec3) -> Result<bool> {
+        count_ray(RayKind::Shadow);
+        let w = *to - *from;
+        let distance = w.length();
+        let shadow_ray = Ray::new(*from, w / distance);
+        Ok(self
+            .scene
+            .hit_test(&shadow_ray, 0.001, distance * 0.999)
+            .is_none())
+    }
+
+    // The balance heuristic weight of building this path from s light and t camera vertices,
+    // found from the ratios of the densities of building it with one more or fewer vertex on
+    // each side. sampled replaces the light path's last vertex when it was found by sampling
+    // the light.
+    fn mis_weight(&self, s: usize, t: usize, sampled: Option<&Vertex>) -> f32 {
+        let qs = sampled.unwrap_or(&self.light_path[s - 1]);
+        let pt = &self.camera_path[t - 1];
+
+        // (pdf_fwd, pdf_rev, delta) for each vertex, as if the two paths had been joined.
+        let info = |vertex: &Vertex| (vertex.pdf_fwd, vertex.pdf_rev, vertex.delta);
+        let mut camera = self.camera_path[..t].iter().map(info).collect::<Vec<_>>();
+        let mut light = self.light_path[..s].iter().map(info).collect::<Vec<_>>();
+        light[s - 1] = info(qs);
+
+        camera[t - 1].1 = qs.pdf(self.camera, pt);
+        if t > 1 {
+            camera[t - 2].1 = pt.pdf(self.camera, &self.camera_path[t - 2]);
+        }
+        light[s - 1].1 = pt.pdf(self.camera, qs);
+        if s > 1 {
+            light[s - 2].1 = qs.pdf(self.camera, &self.light_path[s - 2]);
+        }
+
+        let remap = |pdf: f32| if pdf == 0.0 { 1.0 } else { pdf };
+        let mut sum = 0.0;
+
+        // Fewer camera vertices. A single camera vertex means joining to the camera, which
+        // only works for cameras with importance.
+        let mut ratio = 1.0;
+        for i in (1..t).rev() {
+            ratio *= remap(camera[i].1) / remap(camera[i].0);
+            let joinable = i > 1 || self.camera_connectable();
+            if joinable && !camera[i].2 && !camera[i - 1].2 {
+                sum += ratio;
+            }
+        }
+
+        // Fewer light vertices. No light vertices would mean the camera path hitting the light,
+        // which cannot happen for point and spot lights.
+        let mut ratio = 1.0;
+        for i in (1..s).rev() {
+            ratio *= remap(light[i].1) / remap(light[i].0);
+            if !light[i].2 && !light[i - 1].2 {
+                sum += ratio;
+            }
+        }
+
+        1.0 / (1.0 + sum)
+    }
+
+    fn camera_connectable(&self) -> bool {
+        match self.camera_path.get(1) {
+            Some(vertex) => match (vertex.point - self.camera_path[0].point).unit_vector() {
+                Ok(direction) => self.camera.importance(&direction).is_some(),
+                Err(_) => false,
+            },
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use structopt::StructOpt;
+
+    use super::*;
+    use crate::camera::Perspective;
+    use crate::color::Color;
+    use crate::integrator::radiance;
+    use crate::light::PointLight;
+    use crate::material::Lambertian;
+    use crate::sphere::Sphere;
+    use crate::unit_random::seed_unit_random;
+
+    struct Black;
+
+    impl Environment for Black {
+        fn radiance(&self, _direction: &Vec3) -> Vec3 {
+            Vec3::origin()
+        }
+    }
+
+    #[test]
+    fn test_matches_path_tracing() {
+        // A diffuse ball on a diffuse floor, lit from the side, so that light also bounces
+        // between them. It is found both by camera paths and by light paths joined to the
+        // camera, and the two must add up to the same light.
+        let white = || Lambertian::new(Color::new(0.8, 0.8, 0.8).unwrap());
+        let mut scene = Scene::from_world(vec![
+            Sphere::new(&Vec3::cartesian(0.0, -100.7, -2.0), 100.0, white()).unwrap(),
+            Sphere::new(&Vec3::cartesian(0.0, 0.0, -2.0), 0.7, white()).unwrap(),
+        ]);
+        scene.lights.push(Box::new(PointLight::new(
+            Vec3::cartesian(1.5, 1.0, -1.0),
+            Vec3::cartesian(4.0, 4.0, 4.0),
+        )));
+        let camera = Perspective::new_with_vert_fov(60.0, 1.0).unwrap();
+        let config = Config::from_iter(&["myray"]);
+
+        // The mean over the image, where each light path's splats are shared by every pixel
+        // as IncrementalFrameBuffer does: one light path is traced for each camera sample.
+        let samples = 40000;
+        let mut path = Vec3::origin();
+        let mut bdpt = Vec3::origin();
+        let mut splats = vec![];
+        for sample in 0..samples {
+            seed_unit_random(sample);
+            let ray = camera.get_ray(unit_random(), unit_random()).unwrap();
+            path = path + radiance(&ray, &config, &scene, &Black).unwrap();
+            bdpt =
+                bdpt + bidirectional(&ray, &camera, &config, &scene, &Black, &mut splats).unwrap();
+            for splat in splats.drain(..) {
+                bdpt = bdpt + splat.radiance;
+            }
+        }
+        let path = path / samples as f32;
+        let bdpt = bdpt / samples as f32;
+        assert!(path.x() > 0.01);
+        assert!((path - bdpt).length() < 0.03 * path.length());
+    }
+}
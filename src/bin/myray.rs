@@ -1,95 +1,509 @@
 use std::f32;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 use structopt::StructOpt;
 
 use rays::errors::*;
 use rays::{
-    gradient, load_world, unit_random, Camera, Color, Config, HitTest, IncrementalFrameBuffer, Ray,
-    Screen, World, Worlds,
+    aov_channel_names, denoise, format_duration, load_checkpoint, load_world, metropolis,
+    pass_seed, range_check, render_hash, save_checkpoint, seed_unit_random, wants_aovs, Camera,
+    Config, DenoiseMode, Environment, EnvironmentMap, FeatureBuffers, Filter, FrameBuffer,
+    Gradient, IncrementalFrameBuffer, Integrator, PhotonMap, PreethamSky, Rect, RenderMode,
+    Sampler, Scene, Screen, TimeLimit, Worlds,
 };
 
-use rays::Progress;
-
-fn color(
-    ray: &Ray,
-    hit_test: &impl HitTest,
-    hue: f32,
-    depth: u8,
-    max_depth: u8,
-    background: &Color,
-) -> Result<Color> {
-    if let Some(hit_record) = hit_test.hit_test(ray, 0.001, f32::MAX) {
-        if depth >= max_depth {
-            return Ok(Color::black());
+use rays::{rays_traced, thread_stats, Progress, StatsFormat};
+
+// Only the path integrator traces the render passes and debug views, or renders spectrally.
+fn check_integrator(config: &Config) -> Result<()> {
+    if config.integrator != Integrator::Path
+        && (wants_aovs(config) || config.mode != RenderMode::Beauty)
+    {
+        return Err(ErrorKind::InvalidOptions(
+            "integrator".to_string(),
+            "Only 'path' works with --aov_output, --denoise, or --mode.".to_string(),
+        )
+        .into());
+    }
+    if config.spectral
+        && (config.integrator != Integrator::Path || config.mode != RenderMode::Beauty)
+    {
+        return Err(ErrorKind::InvalidOptions(
+            "spectral".to_string(),
+            "Only works with --integrator path and --mode beauty.".to_string(),
+        )
+        .into());
+    }
+    if config.integrator == Integrator::Metropolis && (config.adaptive || config.crop.is_some()) {
+        return Err(ErrorKind::InvalidOptions(
+            "integrator".to_string(),
+            "'mlt' does not work with --adaptive or --crop.".to_string(),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// Option values that parse but make no sense.
+fn check_ranges(config: &Config) -> Result<()> {
+    if let Some(limit) = config.clamp_indirect {
+        if limit < 0.0 {
+            return Err(ErrorKind::InvalidParam(
+                limit,
+                "--clamp_indirect must not be negative".into(),
+            )
+            .into());
         }
+    }
+    if let Some(roughness) = config.roughen {
+        range_check(roughness, 0.0, 1.0)?;
+    }
+    range_check(config.denoise_iterations as f32, 1.0, 10.0)?;
+    if !config.environment_intensity.is_finite() || config.environment_intensity < 0.0 {
+        return Err(ErrorKind::InvalidParam(
+            config.environment_intensity,
+            "--environment_intensity must be a finite number >= 0.0".into(),
+        )
+        .into());
+    }
+    if config.photon_radius <= 0.0 {
+        return Err(ErrorKind::InvalidParam(
+            config.photon_radius,
+            "--photon_radius must be > 0.0".into(),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn make_environment(config: &Config) -> Result<Box<dyn Environment>> {
+    Ok(match &config.environment {
+        Some(path) => Box::new(EnvironmentMap::open(
+            path,
+            config.environment_rotation,
+            config.environment_intensity,
+        )?),
+        None if config.sky => Box::new(PreethamSky::new(
+            config.sun_elevation,
+            config.sun_azimuth,
+            config.turbidity,
+            config.sky_intensity,
+        )?),
+        None => Box::new(Gradient::new(config.hue)?),
+    })
+}
+
+// An empty frame buffer, or the one saved in --checkpoint when resuming.
+fn make_frame_buffer(config: &Config, scene: &Scene, hash: u64) -> Result<IncrementalFrameBuffer> {
+    let filter = match config.filter_radius {
+        Some(radius) => Filter::new(config.filter, radius)?,
+        None => Filter::with_default_radius(config.filter),
+    };
+    match &config.checkpoint {
+        Some(path) if config.resume => load_checkpoint(path, hash, filter),
+        _ => IncrementalFrameBuffer::with_channels(
+            config.screen_width,
+            config.screen_height,
+            filter,
+            if wants_aovs(config) {
+                aov_channel_names(scene)
+            } else {
+                vec![]
+            },
+        ),
+    }
+}
 
-        if let Some((scattered, attenuation)) = hit_record.material.scatter(ray, &hit_record)? {
-            return Ok(Color::from(
-                attenuation
-                    * color(&scattered, hit_test, hue, depth + 1, max_depth, background)?.as_vec(),
-            ));
+// Identifies everything that affects the image, apart from the number of samples, so that a
+// checkpoint is not resumed with a different scene or settings.
+fn scene_hash(config: &Config, scene: &Scene) -> Result<u64> {
+    let camera = scene
+        .camera
+        .clone()
+        .unwrap_or_else(|| config.camera_settings());
+    let settings = format!(
+        "{:?}",
+        (
+            (config.screen_width, config.screen_height, config.crop),
+            (config.filter, config.filter_radius),
+            (config.adaptive, config.adaptive_error, config.min_samples),
+            (
+                config.max_depth,
+                config.light_sampling,
+                config.mode,
+                config.integrator,
+                config.max_distance,
+            ),
+            (config.clamp_indirect, config.roughen, config.roulette_depth),
+            (config.photons, config.photon_radius, config.final_gather),
+            (config.mutations_per_pixel, config.spectral),
+            (config.ao_distance, config.ao_samples),
+            wants_aovs(config),
+            (
+                &config.environment,
+                config.environment_intensity,
+                config.environment_rotation,
+            ),
+            (config.sky, config.sky_intensity, config.hue),
+            (config.sun_azimuth, config.sun_elevation, config.turbidity),
+        )
+    );
+    Ok(render_hash(&format!(
+        "{}{}{}",
+        serde_yaml::to_string(scene)?,
+        serde_yaml::to_string(&camera)?,
+        settings
+    )))
+}
+
+// Saves the render to --checkpoint, if there is one, every --checkpoint_interval seconds.
+struct Checkpointer<'a> {
+    config: &'a Config,
+    hash: u64,
+    last_save: Instant,
+}
+
+impl<'a> Checkpointer<'a> {
+    fn new(config: &'a Config, hash: u64) -> Checkpointer<'a> {
+        Checkpointer {
+            config,
+            hash,
+            last_save: Instant::now(),
         }
-        Ok(Color::black())
-    } else {
-        let unit_direction = ray.direction().unit_vector()?;
-        let t = 0.5 * (unit_direction.y() + 1.0);
-        Ok(gradient(t, &Color::white(), background))
+    }
+
+    fn after_pass(&mut self, ifb: &IncrementalFrameBuffer) -> Result<()> {
+        if self.last_save.elapsed() >= Duration::from_secs(self.config.checkpoint_interval) {
+            self.save(ifb)?;
+        }
+        Ok(())
+    }
+
+    fn save(&mut self, ifb: &IncrementalFrameBuffer) -> Result<()> {
+        if let Some(path) = &self.config.checkpoint {
+            save_checkpoint(path, self.hash, ifb)?;
+            self.last_save = Instant::now();
+        }
+        Ok(())
     }
 }
 
-fn path_trace_inc(config: &Config, world: &World) -> Result<()> {
-    let background = Color::from_hsv(config.hue, 0.5, 1.0)?;
-    let mut screen = Screen::new(config.screen_width, config.screen_height, config.scale)?;
-    let camera = Camera::new_from_to(
-        &config.look_from,
-        &config.look_at,
-        &config.look_up,
-        config.vfov,
-        screen.height() as f32 / screen.width() as f32,
-    )?;
+// The camera for an animation frame, or the still camera when there is no frame.
+fn make_camera(config: &Config, scene: &Scene, frame: Option<u32>) -> Result<Box<dyn Camera>> {
+    let settings = scene
+        .camera
+        .clone()
+        .unwrap_or_else(|| config.camera_settings());
+    let settings = match (&scene.camera_animation, frame) {
+        (Some(animation), Some(frame)) => animation.settings_at(&settings, frame as f32)?,
+        _ => settings,
+    };
+    settings.build(config.screen_width as f32 / config.screen_height as f32)
+}
 
-    let height = screen.height() as f32;
-    let width = screen.width() as f32;
-    let mut pg = Progress::new(u64::from(config.num_samples));
+// Adds passes of samples to ifb, calling after_pass when each one is complete. frame is the
+// animation frame being rendered, if any.
+fn path_trace_inc<F>(
+    config: &Config,
+    scene: &Scene,
+    camera: &dyn Camera,
+    environment: &dyn Environment,
+    ifb: &mut IncrementalFrameBuffer,
+    frame: Option<u32>,
+    mut after_pass: F,
+) -> Result<()>
+where
+    F: FnMut(&IncrementalFrameBuffer) -> Result<()>,
+{
+    let height = config.screen_height as f32;
+    let width = config.screen_width as f32;
+    let start = Instant::now();
+    let (mut pg, max_passes) = match config.time_limit {
+        Some(TimeLimit(limit)) => (Progress::with_time_limit(limit), u32::MAX),
+        None => (
+            Progress::new(u64::from(config.num_samples)),
+            config.num_samples,
+        ),
+    };
+    pg.set_format(config.progress);
+    pg.set_start(u64::from(ifb.passes()));
+
+    let max_error = f64::from(config.adaptive_error);
+    let min_samples = u32::from(u8::max(config.min_samples, 2));
 
-    let mut ifb = IncrementalFrameBuffer::new(screen.width(), screen.height())?;
+    // Pixels just outside a crop window still splat samples into it.
+    let margin = f32::ceil(ifb.filter().radius()) as usize;
+    let window = render_rect(config)?.expand(margin, ifb.width(), ifb.height());
+    let mut sampler = Sampler::new(config, scene, camera, environment, frame);
 
-    for n in 0..config.num_samples {
-        screen.one_frame(|fb| {
-            for y in 0..config.screen_height {
-                for x in 0..config.screen_width {
-                    let color =
-                        sample_color(config, &world, &camera, x, y, width, height, &background)?;
-                    ifb.set(x, y, color);
+    // A resumed render continues from the passes it already has.
+    for pass in ifb.passes()..max_passes {
+        if let Some(TimeLimit(limit)) = config.time_limit {
+            if start.elapsed() >= limit {
+                break;
+            }
+        }
+
+        let photon_map = if config.integrator == Integrator::PhotonMapping {
+            seed_unit_random(pass_seed(frame, pass));
+            Some(PhotonMap::trace(config, scene, environment, pass)?)
+        } else {
+            None
+        };
+
+        let mut all_converged = true;
+        if config.integrator == Integrator::Metropolis {
+            // The Metropolis integrator samples the whole image at once.
+            all_converged = false;
+            seed_unit_random(pass_seed(frame, pass));
+            let rays = rays_traced();
+            let mutations = metropolis(camera, config, scene, environment, |splat| {
+                ifb.add_light(splat.u * width, splat.v * height, &splat.radiance)
+            })?;
+            ifb.add_light_paths(mutations);
+            pg.add_work(mutations, rays_traced() - rays);
+        } else {
+            for y in window.y0..window.y1 {
+                let rays = rays_traced();
+                let mut samples = 0;
+                for x in window.x0..window.x1 {
+                    if config.adaptive && ifb.converged(x, y, max_error, min_samples) {
+                        continue;
+                    }
+                    all_converged = false;
+                    samples += 1;
+                    sampler.sample_pixel(ifb, photon_map.as_ref(), pass, x, y)?;
                 }
+                pg.add_work(samples, rays_traced() - rays);
+                pg.set_sub((y - window.y0 + 1) as u64, window.height() as u64);
             }
-            ifb.copy_to_fb(u32::from(n), fb);
-            Ok(())
-        })?;
+        }
+        ifb.end_pass();
+        after_pass(ifb)?;
 
+        if all_converged {
+            break;
+        }
         pg.inc();
     }
     pg.finish_and_clear();
 
+    if config.time_limit.is_some() {
+        eprintln!(
+            "Rendered {} passes ({:.1} samples per pixel) in {}",
+            ifb.passes(),
+            mean_samples(config, ifb)?,
+            format_duration(start.elapsed())
+        );
+    }
+    Ok(())
+}
+
+// The mean number of samples taken for each pixel being rendered.
+fn mean_samples(config: &Config, ifb: &IncrementalFrameBuffer) -> Result<f64> {
+    let rect = render_rect(config)?;
+    let mut total = 0u64;
+    for y in rect.y0..rect.y1 {
+        for x in rect.x0..rect.x1 {
+            total += u64::from(ifb.count(x, y));
+        }
+    }
+    Ok(total as f64 / (rect.width() * rect.height()) as f64)
+}
+
+// The part of the image being rendered: the --crop window, or the whole image.
+fn render_rect(config: &Config) -> Result<Rect> {
+    match config.crop {
+        Some(crop) => crop.rect(config.screen_width, config.screen_height),
+        None => Ok(Rect::full(config.screen_width, config.screen_height)),
+    }
+}
+
+// Copies the image in ifb to fb, leaving everything outside the crop window black. The image
+// is denoised if --denoise asks for it at this point: final_image is set for the finished image,
+// and clear for the passes shown while rendering.
+fn copy_image(
+    config: &Config,
+    ifb: &IncrementalFrameBuffer,
+    fb: &mut FrameBuffer,
+    final_image: bool,
+) -> Result<()> {
+    let features = match config.denoise {
+        Some(DenoiseMode::Passes) => FeatureBuffers::from_frame_buffer(ifb),
+        Some(DenoiseMode::Final) if final_image => FeatureBuffers::from_frame_buffer(ifb),
+        _ => None,
+    };
+    match features {
+        Some(features) => fb.copy_from_image(&denoise(
+            &ifb.image(),
+            &features,
+            ifb.width(),
+            ifb.height(),
+            config.denoise_iterations,
+        )),
+        None => ifb.copy_to_fb(fb),
+    }
+    if config.crop.is_some() {
+        fb.clear_outside(&render_rect(config)?);
+    }
+    Ok(())
+}
+
+// Writes fb, or just the crop window if --crop_image is set.
+fn write_image(config: &Config, fb: &FrameBuffer, path: &Path) -> Result<()> {
+    if config.crop_image {
+        fb.crop(&render_rect(config)?)?.write_png(path)
+    } else {
+        fb.write_png(path)
+    }
+}
+
+// Writes the image (and sample heatmap) requested in config. Animation frames get the frame
+// number added to their file names.
+fn save_images(config: &Config, ifb: &IncrementalFrameBuffer, frame: Option<u32>) -> Result<()> {
+    let mut fb = FrameBuffer::new(ifb.width(), ifb.height())?;
+    if let Some(output) = &config.output {
+        copy_image(config, ifb, &mut fb, true)?;
+        write_image(config, &fb, &frame_path(output, "png", frame))?;
+    }
+    if let Some(aov_output) = &config.aov_output {
+        ifb.write_exr(&frame_path(aov_output, "exr", frame), config.aov_float)?;
+    }
+    if let Some(heatmap) = &config.sample_heatmap {
+        ifb.copy_heatmap_to_fb(&mut fb);
+        write_image(config, &fb, &frame_path(heatmap, "png", frame))?;
+    }
+    Ok(())
+}
+
+fn frame_path(path: &PathBuf, ext: &str, frame: Option<u32>) -> PathBuf {
+    let path = add_extension_if_missing(path, ext);
+    match frame {
+        None => path,
+        Some(frame) => {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+            let ext = path.extension().unwrap_or_default().to_string_lossy();
+            path.with_file_name(format!("{}_{:04}.{}", stem, frame, ext))
+        }
+    }
+}
+
+fn render_window(config: &Config, scene: &Scene) -> Result<()> {
+    let environment = make_environment(config)?;
+    let camera = make_camera(config, scene, None)?;
+    let mut screen = Screen::new(config.screen_width, config.screen_height, config.scale)?;
+    let hash = scene_hash(config, scene)?;
+    let mut ifb = make_frame_buffer(config, scene, hash)?;
+    let mut checkpointer = Checkpointer::new(config, hash);
+
+    if let Some(preview) = config.preview {
+        render_preview(
+            config,
+            preview,
+            scene,
+            camera.as_ref(),
+            environment.as_ref(),
+            &mut screen,
+        )?;
+    }
+    path_trace_inc(
+        config,
+        scene,
+        camera.as_ref(),
+        environment.as_ref(),
+        &mut ifb,
+        None,
+        |ifb| {
+            screen.one_frame(|fb| copy_image(config, ifb, fb, false))?;
+            checkpointer.after_pass(ifb)
+        },
+    )?;
+    if config.denoise.is_some() {
+        screen.one_frame(|fb| copy_image(config, &ifb, fb, true))?;
+    }
+    checkpointer.save(&ifb)?;
+    save_images(config, &ifb, None)?;
+    print_stats(config);
+
     screen.wait()
 }
 
-fn sample_color(
+// Shows one pass of a quick integrator in the window, which stays up until the first pass of
+// the full render replaces it.
+fn render_preview(
     config: &Config,
-    world: &World,
-    camera: &Camera,
-    x: usize,
-    y: usize,
-    width: f32,
-    height: f32,
-    background: &Color,
-) -> Result<Color> {
-    let u = (x as f32 + unit_random()) / width;
-    let v = (y as f32 + unit_random()) / height;
-    let ray = camera.get_ray(u, v);
-    color(&ray, world, config.hue, 0, config.max_depth, background)
+    integrator: Integrator,
+    scene: &Scene,
+    camera: &dyn Camera,
+    environment: &dyn Environment,
+    screen: &mut Screen,
+) -> Result<()> {
+    let config = Config {
+        integrator,
+        num_samples: 1,
+        adaptive: false,
+        time_limit: None,
+        aov_output: None,
+        denoise: None,
+        mode: RenderMode::Beauty,
+        ..config.clone()
+    };
+    let mut ifb = IncrementalFrameBuffer::with_channels(
+        config.screen_width,
+        config.screen_height,
+        Filter::with_default_radius(config.filter),
+        vec![],
+    )?;
+    path_trace_inc(&config, scene, camera, environment, &mut ifb, None, |ifb| {
+        screen.one_frame(|fb| copy_image(&config, ifb, fb, false))
+    })
+}
+
+fn render_headless(config: &Config, scene: &Scene) -> Result<()> {
+    let output = config
+        .output
+        .as_ref()
+        .ok_or_else(|| Error::from(ErrorKind::MissingParam("output".to_string())))?;
+    let environment = make_environment(config)?;
+    let hash = scene_hash(config, scene)?;
+
+    let frames = match config.frames {
+        Some(range) => range.frames().map(Some).collect::<Vec<_>>(),
+        None => vec![None],
+    };
+    for frame in frames {
+        if frame.is_some() && frame_path(output, "png", frame).exists() {
+            eprintln!("Skipping {}", frame_path(output, "png", frame).display());
+            continue;
+        }
+
+        let camera = make_camera(config, scene, frame)?;
+        let mut ifb = make_frame_buffer(config, scene, hash)?;
+        let mut checkpointer = Checkpointer::new(config, hash);
+        path_trace_inc(
+            config,
+            scene,
+            camera.as_ref(),
+            environment.as_ref(),
+            &mut ifb,
+            frame,
+            |ifb| checkpointer.after_pass(ifb),
+        )?;
+        checkpointer.save(&ifb)?;
+        save_images(config, &ifb, frame)?;
+    }
+    print_stats(config);
+    Ok(())
+}
+
+fn print_stats(config: &Config) {
+    match config.stats {
+        Some(StatsFormat::Table) => print!("{}", thread_stats().table()),
+        Some(StatsFormat::Json) => println!("{}", thread_stats().json()),
+        None => {}
+    }
 }
 
 // fn path_trace(config: &Config, world: &World) -> Result<()> {
@@ -137,33 +551,38 @@ fn add_extension_if_missing(path: &PathBuf, ext: &str) -> PathBuf {
     result
 }
 
-fn get_world(config: &Config) -> Result<World> {
+fn get_scene(config: &Config) -> Result<Scene> {
     if config.world_files.is_empty() {
-        load_world(config.world.unwrap_or(Worlds::ThreeBalls))
+        Ok(Scene::from_world(load_world(
+            config.world.unwrap_or(Worlds::ThreeBalls),
+        )?))
     } else {
-        config.world_files.iter().try_fold(vec![], |v, filename| {
-            let file = File::open(filename)?;
-            let small_world = serde_yaml::from_reader::<_, World>(file)?;
-            Ok(v.into_iter()
-                .chain(small_world.into_iter())
-                .collect::<World>())
-        })
+        config
+            .world_files
+            .iter()
+            .try_fold(Scene::default(), |scene, filename| {
+                Ok(scene.merge(Scene::open(filename)?))
+            })
     }
 }
 
 fn real_main() -> Result<()> {
     let config = Config::from_args();
-    let world = get_world(&config)?;
+    check_integrator(&config)?;
+    check_ranges(&config)?;
+    let scene = get_scene(&config)?;
 
     if let Some(write) = &config.write_world {
         let filename = add_extension_if_missing(&write, "yaml");
         let file = File::create(filename)?;
-        serde_yaml::to_writer(file, &world)?;
+        serde_yaml::to_writer(file, &scene)?;
     }
 
-    path_trace_inc(&config, &world)?;
-
-    Ok(())
+    if config.headless {
+        render_headless(&config, &scene)
+    } else {
+        render_window(&config, &scene)
+    }
 }
 
 fn main() {
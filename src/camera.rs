@@ -1,22 +1,155 @@
+use std::f32;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
 use crate::errors::*;
 use crate::ray::Ray;
-use crate::vec3::{cross, Vec3};
+use crate::util::random_in_unit_disk;
+use crate::vec3::{cross, dot, Vec3};
+
+// Generates the ray for a point on the image. u and v are in [0, 1], with (0, 0) at the lower
+// left. Returns None for points that the projection does not cover.
+pub trait Camera {
+    fn get_ray(&self, u: f32, v: f32) -> Option<Ray>;
+
+    // Where a ray leaving the camera in direction (a unit vector) falls on the image, for
+    // connecting light paths to the camera. Only pinhole cameras can be connected to, so the
+    // default is None.
+    fn importance(&self, _direction: &Vec3) -> Option<Importance> {
+        None
+    }
+}
+
+// How a pinhole camera sees a direction: see Camera::importance.
+#[derive(Debug, Copy, Clone)]
+pub struct Importance {
+    // The point on the image, as for get_ray.
+    pub u: f32,
+    pub v: f32,
+
+    // Cosine of the angle between the direction and the view direction.
+    pub cosine: f32,
+
+    // The importance (We) of the ray, normalized to integrate to 1 over the image.
+    pub importance: f32,
+
+    // The density, with respect to solid angle, of get_ray choosing the direction for a random
+    // point on the image.
+    pub pdf: f32,
+}
+
+fn check_finite(name: &str, vec: &Vec3) -> Result<()> {
+    if vec.x().is_finite() && vec.y().is_finite() && vec.z().is_finite() {
+        Ok(())
+    } else {
+        Err(ErrorKind::NonFiniteVector(name.to_string(), format!("{:?}", vec)).into())
+    }
+}
+
+fn check_param(name: &str, val: f32, valid: bool, reason: &str) -> Result<()> {
+    if valid && val.is_finite() {
+        Ok(())
+    } else {
+        Err(ErrorKind::InvalidCameraParam(name.to_string(), val, reason.to_string()).into())
+    }
+}
+
+fn check_aspect(aspect: f32) -> Result<()> {
+    check_param("aspect", aspect, aspect > 0.0, "must be > 0.0")
+}
+
+// The world axis that is furthest from being parallel to w.
+fn alternative_up(w: &Vec3) -> Vec3 {
+    let (x, y, z) = (w.x().abs(), w.y().abs(), w.z().abs());
+    if y <= x && y <= z {
+        Vec3::cartesian(0.0, 1.0, 0.0)
+    } else if z <= x {
+        Vec3::cartesian(0.0, 0.0, 1.0)
+    } else {
+        Vec3::cartesian(1.0, 0.0, 0.0)
+    }
+}
+
+// Position and orientation shared by all of the cameras.
+// w points backwards (away from what the camera sees), u to the right, and v up.
+#[derive(Debug, Clone, Copy)]
+struct Basis {
+    origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+}
+
+impl Basis {
+    // If vup is zero or parallel to the view direction, a different up vector is chosen and a
+    // warning is printed.
+    fn new(lookfrom: &Vec3, lookat: &Vec3, vup: &Vec3) -> Result<Basis> {
+        check_finite("look_from", lookfrom)?;
+        check_finite("look_at", lookat)?;
+        check_finite("look_up", vup)?;
+
+        let view = lookfrom - lookat;
+        if view.length() == 0.0 {
+            return Err(ErrorKind::CameraLookAtSelf(format!("{:?}", lookfrom)).into());
+        }
+        let w = view.unit_vector()?;
+
+        let up_cross_w = cross(vup, &w);
+        let u = if vup.length() > 0.0 && up_cross_w.length() > 1.0e-4 * vup.length() {
+            up_cross_w.unit_vector()?
+        } else {
+            let alternative = alternative_up(&w);
+            eprintln!(
+                "Warning: look_up {:?} is parallel to the view direction. Using {:?} instead.",
+                vup, alternative
+            );
+            cross(&alternative, &w).unit_vector()?
+        };
+        let v = cross(&w, &u);
+
+        Ok(Basis {
+            origin: *lookfrom,
+            u,
+            v,
+            w,
+        })
+    }
+
+    // The same basis, moved sideways (along u) by offset.
+    fn shifted(&self, offset: f32) -> Basis {
+        Basis {
+            origin: self.origin + offset * self.u,
+            ..*self
+        }
+    }
+
+    // Converts a direction in camera space (x right, y up, z backwards) to world space.
+    fn world_direction(&self, x: f32, y: f32, z: f32) -> Vec3 {
+        x * self.u + y * self.v + z * self.w
+    }
+}
 
 #[derive(Debug)]
-pub struct Camera {
+pub struct Perspective {
     origin: Vec3,
     lower_left_corner: Vec3,
     horizontal: Vec3,
     vertical: Vec3,
+    forward: Vec3,
+    // Unit vectors across the lens, and its radius. A radius of 0 is a pinhole camera.
+    lens_u: Vec3,
+    lens_v: Vec3,
+    lens_radius: f32,
 }
 
-impl Camera {
-    pub fn new() -> Result<Camera> {
-        Camera::new_with_vert_fov(90.0, 2.0)
+impl Perspective {
+    pub fn new() -> Result<Perspective> {
+        Perspective::new_with_vert_fov(90.0, 2.0)
     }
 
-    pub fn new_with_vert_fov(vfov: f32, aspect: f32) -> Result<Camera> {
-        Camera::new_from_to(
+    pub fn new_with_vert_fov(vfov: f32, aspect: f32) -> Result<Perspective> {
+        Perspective::new_from_to(
             &Vec3::cartesian(0.0, 0.0, 0.0),
             &Vec3::cartesian(0.0, 0.0, -1.0),
             &Vec3::cartesian(0.0, 1.0, 0.0),
@@ -25,35 +158,614 @@ impl Camera {
         )
     }
 
+    // aspect is width / height.
     pub fn new_from_to(
         lookfrom: &Vec3,
         lookat: &Vec3,
         vup: &Vec3,
         vfov: f32,
         aspect: f32,
-    ) -> Result<Camera> {
-        // TODO: check that lookfrom and lookat are not the same.
+    ) -> Result<Perspective> {
+        Perspective::from_basis(&Basis::new(lookfrom, lookat, vup)?, vfov, aspect, 0.0, 1.0)
+    }
+
+    // A thin lens camera: points focus_distance away along the view direction are in focus,
+    // and aperture is the diameter of the lens.
+    pub fn new_with_lens(
+        lookfrom: &Vec3,
+        lookat: &Vec3,
+        vup: &Vec3,
+        vfov: f32,
+        aspect: f32,
+        aperture: f32,
+        focus_distance: f32,
+    ) -> Result<Perspective> {
+        Perspective::from_basis(
+            &Basis::new(lookfrom, lookat, vup)?,
+            vfov,
+            aspect,
+            aperture,
+            focus_distance,
+        )
+    }
+
+    fn from_basis(
+        basis: &Basis,
+        vfov: f32,
+        aspect: f32,
+        aperture: f32,
+        focus_distance: f32,
+    ) -> Result<Perspective> {
+        check_param(
+            "vfov",
+            vfov,
+            vfov > 0.0 && vfov < 180.0,
+            "must be between 0 and 180 degrees",
+        )?;
+        check_aspect(aspect)?;
+        check_param("aperture", aperture, aperture >= 0.0, "must be >= 0.0")?;
+        check_param(
+            "focus_distance",
+            focus_distance,
+            focus_distance > 0.0,
+            "must be > 0.0",
+        )?;
+
         let theta = vfov * std::f32::consts::PI / 180.0;
         let half_height = f32::tan(theta / 2.0);
         let half_width = half_height * aspect;
-        let origin = lookfrom;
-        // TODO: Check that w,u,v are all non-zero, non-NaN vectors.
-        let w = (lookfrom - lookat).unit_vector()?;
-        let u = cross(&vup, &w).unit_vector()?;
-        let v = cross(&w, &u);
+        let Basis { origin, u, v, w } = *basis;
 
-        Ok(Camera {
-            lower_left_corner: origin - half_width * u - half_height * v - w,
-            horizontal: 2.0 * half_width * u,
-            vertical: 2.0 * half_height * v,
-            origin: *origin,
+        // The image plane is placed at the focus distance.
+        let fd = focus_distance;
+        Ok(Perspective {
+            lower_left_corner: origin - fd * half_width * u - fd * half_height * v - fd * w,
+            horizontal: 2.0 * fd * half_width * u,
+            vertical: 2.0 * fd * half_height * v,
+            forward: -w,
+            origin,
+            lens_u: u,
+            lens_v: v,
+            lens_radius: aperture / 2.0,
         })
     }
+}
+
+impl Camera for Perspective {
+    fn get_ray(&self, u: f32, v: f32) -> Option<Ray> {
+        let origin = if self.lens_radius > 0.0 {
+            let (dx, dy) = random_in_unit_disk();
+            self.origin + self.lens_radius * (dx * self.lens_u + dy * self.lens_v)
+        } else {
+            self.origin
+        };
+        Some(Ray::new(
+            origin,
+            self.lower_left_corner + u * self.horizontal + v * self.vertical - origin,
+        ))
+    }
 
-    pub fn get_ray(&self, u: f32, v: f32) -> Ray {
-        Ray::new(
-            self.origin,
-            self.lower_left_corner + u * self.horizontal + v * self.vertical - self.origin,
+    fn importance(&self, direction: &Vec3) -> Option<Importance> {
+        let cosine = dot(direction, &self.forward);
+        if self.lens_radius > 0.0 || cosine <= 0.0 {
+            return None;
+        }
+
+        // Where direction meets the image plane, relative to its lower left corner.
+        let corner = self.lower_left_corner - self.origin;
+        let distance = dot(&corner, &self.forward);
+        let on_plane = (distance / cosine) * direction - corner;
+        let u = dot(&on_plane, &self.horizontal) / self.horizontal.squared_length();
+        let v = dot(&on_plane, &self.vertical) / self.vertical.squared_length();
+        if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) {
+            return None;
+        }
+
+        // The area of the image, moved to one unit in front of the camera.
+        let area = self.horizontal.length() * self.vertical.length() / (distance * distance);
+        let cos3 = cosine * cosine * cosine;
+        Some(Importance {
+            u,
+            v,
+            cosine,
+            importance: 1.0 / (area * cos3 * cosine),
+            pdf: 1.0 / (area * cos3),
+        })
+    }
+}
+
+// Parallel rays from a rectangle view_width units wide.
+#[derive(Debug)]
+pub struct Orthographic {
+    basis: Basis,
+    view_width: f32,
+    view_height: f32,
+}
+
+impl Orthographic {
+    // aspect is width / height.
+    pub fn new_from_to(
+        lookfrom: &Vec3,
+        lookat: &Vec3,
+        vup: &Vec3,
+        view_width: f32,
+        aspect: f32,
+    ) -> Result<Orthographic> {
+        Orthographic::from_basis(&Basis::new(lookfrom, lookat, vup)?, view_width, aspect)
+    }
+
+    fn from_basis(basis: &Basis, view_width: f32, aspect: f32) -> Result<Orthographic> {
+        check_param("view_width", view_width, view_width > 0.0, "must be > 0.0")?;
+        check_aspect(aspect)?;
+
+        Ok(Orthographic {
+            basis: *basis,
+            view_width,
+            view_height: view_width / aspect,
+        })
+    }
+}
+
+impl Camera for Orthographic {
+    fn get_ray(&self, u: f32, v: f32) -> Option<Ray> {
+        let offset = self.basis.world_direction(
+            (u - 0.5) * self.view_width,
+            (v - 0.5) * self.view_height,
+            0.0,
+        );
+        Some(Ray::new(self.basis.origin + offset, -self.basis.w))
+    }
+}
+
+// An equidistant ("f-theta") fisheye. The image circle fills the height of the image and
+// covers fov degrees; points outside of it are not rendered.
+#[derive(Debug)]
+pub struct Fisheye {
+    basis: Basis,
+    half_fov: f32, // radians
+    aspect: f32,
+}
+
+impl Fisheye {
+    // aspect is width / height.
+    pub fn new_from_to(
+        lookfrom: &Vec3,
+        lookat: &Vec3,
+        vup: &Vec3,
+        fov: f32,
+        aspect: f32,
+    ) -> Result<Fisheye> {
+        Fisheye::from_basis(&Basis::new(lookfrom, lookat, vup)?, fov, aspect)
+    }
+
+    fn from_basis(basis: &Basis, fov: f32, aspect: f32) -> Result<Fisheye> {
+        check_param(
+            "fisheye_fov",
+            fov,
+            fov > 0.0 && fov <= 360.0,
+            "must be between 0 and 360 degrees",
+        )?;
+        check_aspect(aspect)?;
+
+        Ok(Fisheye {
+            basis: *basis,
+            half_fov: fov.to_radians() / 2.0,
+            aspect,
+        })
+    }
+}
+
+impl Camera for Fisheye {
+    fn get_ray(&self, u: f32, v: f32) -> Option<Ray> {
+        let x = (2.0 * u - 1.0) * self.aspect;
+        let y = 2.0 * v - 1.0;
+        let r = f32::sqrt(x * x + y * y);
+        if r > 1.0 {
+            return None;
+        }
+
+        let theta = r * self.half_fov;
+        let phi = f32::atan2(y, x);
+        let direction = self.basis.world_direction(
+            f32::sin(theta) * f32::cos(phi),
+            f32::sin(theta) * f32::sin(phi),
+            -f32::cos(theta),
+        );
+        Some(Ray::new(self.basis.origin, direction))
+    }
+}
+
+// A latitude/longitude panorama, centered on the view direction. It always covers 180 degrees
+// vertically; horizontal_fov is 360 for a full panorama, or 180 for VR180.
+#[derive(Debug)]
+pub struct Equirectangular {
+    basis: Basis,
+    horizontal_fov: f32, // radians
+    // For omni-directional stereo: each ray starts this far to the right of the center of
+    // projection, perpendicular to its own horizontal direction. Zero for mono panoramas.
+    eye_offset: f32,
+}
+
+impl Equirectangular {
+    pub fn new_from_to(
+        lookfrom: &Vec3,
+        lookat: &Vec3,
+        vup: &Vec3,
+        horizontal_fov: f32,
+    ) -> Result<Equirectangular> {
+        Equirectangular::from_basis(&Basis::new(lookfrom, lookat, vup)?, horizontal_fov, 0.0)
+    }
+
+    fn from_basis(basis: &Basis, horizontal_fov: f32, eye_offset: f32) -> Result<Equirectangular> {
+        check_param(
+            "panorama_fov",
+            horizontal_fov,
+            horizontal_fov > 0.0 && horizontal_fov <= 360.0,
+            "must be between 0 and 360 degrees",
+        )?;
+
+        Ok(Equirectangular {
+            basis: *basis,
+            horizontal_fov: horizontal_fov.to_radians(),
+            eye_offset,
+        })
+    }
+}
+
+impl Camera for Equirectangular {
+    fn get_ray(&self, u: f32, v: f32) -> Option<Ray> {
+        let phi = self.horizontal_fov * (u - 0.5);
+        let theta = f32::consts::PI * (1.0 - v);
+        let direction = self.basis.world_direction(
+            f32::sin(theta) * f32::sin(phi),
+            f32::cos(theta),
+            -f32::sin(theta) * f32::cos(phi),
+        );
+        let origin = self.basis.origin
+            + self.eye_offset
+                * self
+                    .basis
+                    .world_direction(f32::cos(phi), 0.0, f32::sin(phi));
+        Some(Ray::new(origin, direction))
+    }
+}
+
+// How the two eyes of a stereo pair are arranged in the image.
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum StereoLayout {
+    // Left eye in the left half of the image.
+    SideBySide,
+    // Left eye in the top half of the image.
+    OverUnder,
+}
+
+impl FromStr for StereoLayout {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<StereoLayout> {
+        match s.to_lowercase().as_str() {
+            "side-by-side" | "sbs" => Ok(StereoLayout::SideBySide),
+            "over-under" | "ou" => Ok(StereoLayout::OverUnder),
+            _ => Err(ErrorKind::ParseError(
+                s.to_string(),
+                "Must be 'side-by-side' or 'over-under'.".to_string(),
+            )
+            .into()),
+        }
+    }
+}
+
+// Renders a camera for each eye into its own half of the image.
+pub struct Stereo {
+    left: Box<dyn Camera>,
+    right: Box<dyn Camera>,
+    layout: StereoLayout,
+}
+
+impl Camera for Stereo {
+    fn get_ray(&self, u: f32, v: f32) -> Option<Ray> {
+        match self.layout {
+            StereoLayout::SideBySide if u < 0.5 => self.left.get_ray(2.0 * u, v),
+            StereoLayout::SideBySide => self.right.get_ray(2.0 * u - 1.0, v),
+            StereoLayout::OverUnder if v >= 0.5 => self.left.get_ray(u, 2.0 * v - 1.0),
+            StereoLayout::OverUnder => self.right.get_ray(u, 2.0 * v),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub enum Projection {
+    Perspective,
+    Orthographic,
+    Fisheye,
+    Equirectangular,
+}
+
+impl FromStr for Projection {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Projection> {
+        match s.to_lowercase().as_str() {
+            "perspective" => Ok(Projection::Perspective),
+            "orthographic" => Ok(Projection::Orthographic),
+            "fisheye" => Ok(Projection::Fisheye),
+            "equirectangular" => Ok(Projection::Equirectangular),
+            _ => Err(ErrorKind::ParseError(
+                s.to_string(),
+                "Must be 'perspective', 'orthographic', 'fisheye', or 'equirectangular'."
+                    .to_string(),
+            )
+            .into()),
+        }
+    }
+}
+
+// A description of a camera, as given on the command line or in a scene file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CameraSettings {
+    #[serde(default = "default_projection")]
+    pub projection: Projection,
+    pub look_from: Vec3,
+    pub look_at: Vec3,
+    #[serde(default = "default_look_up")]
+    pub look_up: Vec3,
+    #[serde(default = "default_vfov")]
+    pub vfov: f32,
+    #[serde(default = "default_view_width")]
+    pub view_width: f32,
+    #[serde(default = "default_fisheye_fov")]
+    pub fisheye_fov: f32,
+    #[serde(default = "default_panorama_fov")]
+    pub panorama_fov: f32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stereo: Option<StereoLayout>,
+    // Interpupillary distance, in world units.
+    #[serde(default = "default_ipd")]
+    pub ipd: f32,
+    // Diameter of the lens, for depth of field. Zero for a pinhole camera.
+    #[serde(default)]
+    pub aperture: f32,
+    // Distance to the plane in focus. Defaults to the distance from look_from to look_at.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focus_distance: Option<f32>,
+}
+
+fn default_projection() -> Projection {
+    Projection::Perspective
+}
+
+fn default_look_up() -> Vec3 {
+    Vec3::cartesian(0.0, 1.0, 0.0)
+}
+
+fn default_vfov() -> f32 {
+    90.0
+}
+
+fn default_view_width() -> f32 {
+    2.0
+}
+
+fn default_fisheye_fov() -> f32 {
+    180.0
+}
+
+fn default_panorama_fov() -> f32 {
+    360.0
+}
+
+fn default_ipd() -> f32 {
+    0.064
+}
+
+impl CameraSettings {
+    // aspect is width / height of the whole image.
+    pub fn build(&self, aspect: f32) -> Result<Box<dyn Camera>> {
+        let basis = Basis::new(&self.look_from, &self.look_at, &self.look_up)?;
+        match self.stereo {
+            None => self.build_eye(&basis, aspect, 0.0),
+            Some(layout) => {
+                check_param("ipd", self.ipd, self.ipd >= 0.0, "must be >= 0.0")?;
+                let eye_aspect = match layout {
+                    StereoLayout::SideBySide => aspect / 2.0,
+                    StereoLayout::OverUnder => aspect * 2.0,
+                };
+                Ok(Box::new(Stereo {
+                    left: self.build_eye(&basis, eye_aspect, -self.ipd / 2.0)?,
+                    right: self.build_eye(&basis, eye_aspect, self.ipd / 2.0)?,
+                    layout,
+                }))
+            }
+        }
+    }
+
+    // eye_offset moves the camera to the right (or left, if negative). Panoramas use
+    // omni-directional stereo instead, since no single offset works in every direction.
+    fn build_eye(&self, basis: &Basis, aspect: f32, eye_offset: f32) -> Result<Box<dyn Camera>> {
+        let shifted = basis.shifted(eye_offset);
+        Ok(match self.projection {
+            Projection::Perspective => {
+                let focus_distance = self
+                    .focus_distance
+                    .unwrap_or_else(|| (self.look_from - self.look_at).length());
+                Box::new(Perspective::from_basis(
+                    &shifted,
+                    self.vfov,
+                    aspect,
+                    self.aperture,
+                    focus_distance,
+                )?)
+            }
+            Projection::Orthographic => {
+                Box::new(Orthographic::from_basis(&shifted, self.view_width, aspect)?)
+            }
+            Projection::Fisheye => {
+                Box::new(Fisheye::from_basis(&shifted, self.fisheye_fov, aspect)?)
+            }
+            Projection::Equirectangular => Box::new(Equirectangular::from_basis(
+                basis,
+                self.panorama_fov,
+                eye_offset,
+            )?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_close(a: &Vec3, b: &Vec3) {
+        assert!((a - b).length() < 1.0e-5, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn test_projections() {
+        let settings = |projection| CameraSettings {
+            projection,
+            look_from: Vec3::origin(),
+            look_at: Vec3::cartesian(0.0, 0.0, -1.0),
+            look_up: default_look_up(),
+            vfov: 90.0,
+            view_width: 4.0,
+            fisheye_fov: 180.0,
+            panorama_fov: 360.0,
+            stereo: None,
+            ipd: 0.064,
+            aperture: 0.0,
+            focus_distance: None,
+        };
+        let forward = Vec3::cartesian(0.0, 0.0, -1.0);
+
+        for projection in &[
+            Projection::Perspective,
+            Projection::Orthographic,
+            Projection::Fisheye,
+            Projection::Equirectangular,
+        ] {
+            let camera = settings(*projection).build(2.0).unwrap();
+            let ray = camera.get_ray(0.5, 0.5).unwrap();
+            assert_close(&forward, &ray.direction().unit_vector().unwrap());
+        }
+
+        let ortho = settings(Projection::Orthographic).build(2.0).unwrap();
+        let corner = ortho.get_ray(0.0, 0.0).unwrap();
+        assert_close(&Vec3::cartesian(-2.0, -1.0, 0.0), corner.origin());
+        assert_close(&forward, corner.direction());
+
+        let fisheye = settings(Projection::Fisheye).build(2.0).unwrap();
+        assert!(fisheye.get_ray(0.0, 0.0).is_none());
+        let edge = fisheye.get_ray(0.75, 0.5).unwrap();
+        assert_close(&Vec3::cartesian(1.0, 0.0, 0.0), edge.direction());
+
+        let panorama = settings(Projection::Equirectangular).build(2.0).unwrap();
+        let behind = panorama.get_ray(0.0, 0.5).unwrap();
+        assert_close(&Vec3::cartesian(0.0, 0.0, 1.0), behind.direction());
+        let up = panorama.get_ray(0.5, 1.0).unwrap();
+        assert_close(&Vec3::cartesian(0.0, 1.0, 0.0), up.direction());
+    }
+
+    #[test]
+    fn test_importance() {
+        let camera = Perspective::new_from_to(
+            &Vec3::origin(),
+            &Vec3::cartesian(0.0, 0.0, -1.0),
+            &default_look_up(),
+            90.0,
+            2.0,
+        )
+        .unwrap();
+        let ray = camera.get_ray(0.25, 0.75).unwrap();
+        let importance = camera
+            .importance(&ray.direction().unit_vector().unwrap())
+            .unwrap();
+        assert!((importance.u - 0.25).abs() < 1.0e-5);
+        assert!((importance.v - 0.75).abs() < 1.0e-5);
+
+        // The image is 4 x 2 units at distance 1, so straight ahead the pdf is 1 / 8.
+        let center = camera.importance(&Vec3::cartesian(0.0, 0.0, -1.0)).unwrap();
+        assert!((center.pdf - 0.125).abs() < 1.0e-6);
+        assert!(camera.importance(&Vec3::cartesian(0.0, 0.0, 1.0)).is_none());
+
+        let lens = Perspective::new_with_lens(
+            &Vec3::origin(),
+            &Vec3::cartesian(0.0, 0.0, -1.0),
+            &default_look_up(),
+            90.0,
+            2.0,
+            0.1,
+            1.0,
         )
+        .unwrap();
+        assert!(lens.importance(&Vec3::cartesian(0.0, 0.0, -1.0)).is_none());
+    }
+
+    #[test]
+    fn test_stereo() {
+        let mut settings = CameraSettings {
+            projection: Projection::Perspective,
+            look_from: Vec3::origin(),
+            look_at: Vec3::cartesian(0.0, 0.0, -1.0),
+            look_up: default_look_up(),
+            vfov: 90.0,
+            view_width: 2.0,
+            fisheye_fov: 180.0,
+            panorama_fov: 360.0,
+            stereo: Some(StereoLayout::SideBySide),
+            ipd: 0.2,
+            aperture: 0.0,
+            focus_distance: None,
+        };
+
+        // The centers of each half look straight ahead from each eye.
+        let camera = settings.build(2.0).unwrap();
+        let left = camera.get_ray(0.25, 0.5).unwrap();
+        let right = camera.get_ray(0.75, 0.5).unwrap();
+        assert_close(&Vec3::cartesian(-0.1, 0.0, 0.0), left.origin());
+        assert_close(&Vec3::cartesian(0.1, 0.0, 0.0), right.origin());
+        assert_close(left.direction(), right.direction());
+
+        // Omni-directional stereo: looking to the right, the left eye is in front.
+        settings.projection = Projection::Equirectangular;
+        settings.stereo = Some(StereoLayout::OverUnder);
+        let camera = settings.build(1.0).unwrap();
+        let left = camera.get_ray(0.75, 0.75).unwrap();
+        let right = camera.get_ray(0.75, 0.25).unwrap();
+        assert_close(&Vec3::cartesian(1.0, 0.0, 0.0), left.direction());
+        assert_close(&Vec3::cartesian(0.0, 0.0, -0.1), left.origin());
+        assert_close(&Vec3::cartesian(0.0, 0.0, 0.1), right.origin());
+    }
+
+    #[test]
+    fn test_validation() {
+        let from = Vec3::origin();
+        let at = Vec3::cartesian(0.0, 0.0, -1.0);
+        let up = default_look_up();
+
+        // A look_up parallel to the view direction is replaced.
+        let down =
+            Perspective::new_from_to(&from, &Vec3::cartesian(0.0, -2.0, 0.0), &up, 90.0, 1.0)
+                .unwrap();
+        let ray = down.get_ray(0.5, 0.5).unwrap();
+        assert_close(&Vec3::cartesian(0.0, -1.0, 0.0), ray.direction());
+        let corner = down.get_ray(0.0, 0.0).unwrap();
+        assert!(corner.direction().x().is_finite());
+
+        assert!(matches!(
+            Perspective::new_from_to(&from, &from, &up, 90.0, 1.0),
+            Err(Error(ErrorKind::CameraLookAtSelf(_), _))
+        ));
+        assert!(matches!(
+            Perspective::new_from_to(&from, &at, &up, 180.0, 1.0),
+            Err(Error(ErrorKind::InvalidCameraParam(_, _, _), _))
+        ));
+        assert!(matches!(
+            Perspective::new_from_to(&from, &at, &up, 90.0, 0.0),
+            Err(Error(ErrorKind::InvalidCameraParam(_, _, _), _))
+        ));
+        assert!(matches!(
+            Perspective::new_from_to(&Vec3::cartesian(f32::NAN, 0.0, 0.0), &at, &up, 90.0, 1.0),
+            Err(Error(ErrorKind::NonFiniteVector(_, _), _))
+        ));
     }
 }
@@ -0,0 +1,98 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::errors::*;
+use crate::fb::IncrementalFrameBuffer;
+use crate::filter::Filter;
+
+const MAGIC: &[u8] = b"myray checkpoint 2\n";
+
+// A stable (FNV-1a) hash of a description of everything that affects the rendered image, so
+// that a checkpoint is only resumed by the render that wrote it.
+pub fn render_hash(description: &str) -> u64 {
+    description
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+// Writes the accumulated samples to path. The random numbers for each sample are seeded from
+// the pass and the pixel, so the pass count in ifb is all the random state needed to resume.
+//
+// The checkpoint is written to a temporary file first so that an interrupted write never
+// destroys the previous checkpoint.
+pub fn save_checkpoint(path: &Path, hash: u64, ifb: &IncrementalFrameBuffer) -> Result<()> {
+    let temp_path = path.with_extension("tmp");
+    {
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&hash.to_le_bytes())?;
+        ifb.write_sums(&mut writer)?;
+        writer.flush()?;
+    }
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+// Reads a checkpoint written by save_checkpoint, failing if it came from a different render.
+pub fn load_checkpoint(path: &Path, hash: u64, filter: Filter) -> Result<IncrementalFrameBuffer> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut magic = [0u8; MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(ErrorKind::InvalidCheckpoint(
+            path.display().to_string(),
+            "Not a myray checkpoint file.".to_string(),
+        )
+        .into());
+    }
+
+    let mut saved_hash = [0u8; 8];
+    reader.read_exact(&mut saved_hash)?;
+    if u64::from_le_bytes(saved_hash) != hash {
+        return Err(ErrorKind::InvalidCheckpoint(
+            path.display().to_string(),
+            "The scene or render settings have changed since it was written.".to_string(),
+        )
+        .into());
+    }
+
+    IncrementalFrameBuffer::read_sums(&mut reader, filter)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::fb::FrameBuffer;
+    use crate::filter::FilterKind;
+    use crate::vec3::Vec3;
+
+    #[test]
+    fn test_round_trip() {
+        let filter = Filter::with_default_radius(FilterKind::Tent);
+        let mut ifb = IncrementalFrameBuffer::with_filter(3, 2, filter).unwrap();
+        ifb.splat(1.25, 0.5, &Vec3::cartesian(0.25, 0.5, 0.75));
+        ifb.end_pass();
+
+        let path = std::env::temp_dir().join(format!("myray_test_{}.ckpt", std::process::id()));
+        save_checkpoint(&path, render_hash("scene"), &ifb).unwrap();
+        let loaded = load_checkpoint(&path, render_hash("scene"), filter).unwrap();
+        let changed = load_checkpoint(&path, render_hash("other scene"), filter);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            (3, 2, 1),
+            (loaded.width(), loaded.height(), loaded.passes())
+        );
+        assert_eq!(1, loaded.count(1, 0));
+        let mut expected = FrameBuffer::new(3, 2).unwrap();
+        let mut actual = FrameBuffer::new(3, 2).unwrap();
+        ifb.copy_to_fb(&mut expected);
+        loaded.copy_to_fb(&mut actual);
+        assert_eq!(expected.buffer(), actual.buffer());
+        assert!(changed.is_err());
+    }
+}
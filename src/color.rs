@@ -84,6 +84,15 @@ impl Color {
         Color::new(0.0, 0.0, 1.0).unwrap()
     }
 
+    // Relative luminance using the Rec. 709 primaries.
+    pub fn luminance(&self) -> f32 {
+        Color::luminance_of(self.r, self.g, self.b)
+    }
+
+    pub fn luminance_of(r: f32, g: f32, b: f32) -> f32 {
+        0.2126 * r + 0.7152 * g + 0.0722 * b
+    }
+
     pub fn as_vec(&self) -> Vec3 {
         Vec3::cartesian(self.r, self.g, self.b)
     }
@@ -1,19 +1,177 @@
+use std::fmt;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 use minifb::Scale;
 
+use crate::camera::{CameraSettings, Projection, StereoLayout};
+use crate::debug::RenderMode;
+use crate::denoise::DenoiseMode;
 use crate::errors::*;
+use crate::fb::Rect;
+use crate::filter::FilterKind;
+use crate::integrator::Integrator;
+use crate::pg::ProgressFormat;
+use crate::stats::StatsFormat;
 use crate::vec3::Vec3;
 use crate::world::Worlds;
 
-#[derive(StructOpt, Debug)]
+#[derive(StructOpt, Debug, Clone)]
 #[structopt()]
 pub struct Config {
-    /// Hue for the background color.
+    /// Stop sampling pixels once their estimated error falls below --adaptive_error.
+    /// --num_samples becomes the maximum number of samples per pixel.
+    #[structopt(long)]
+    pub adaptive: bool,
+
+    /// Target relative standard error of a pixel's mean luminance for adaptive sampling.
+    #[structopt(long, default_value = "0.05", visible_alias = "ae")]
+    pub adaptive_error: f32,
+
+    /// How far from a surface --integrator ao looks for things blocking the sky, in world
+    /// units.
+    #[structopt(long, default_value = "1")]
+    pub ao_distance: f32,
+
+    /// Rays traced from each surface seen by --integrator ao.
+    #[structopt(long, default_value = "16")]
+    pub ao_samples: u32,
+
+    /// Write 32-bit floats to --aov_output instead of half floats.
+    #[structopt(long, requires = "aov_output")]
+    pub aov_float: bool,
+
+    /// Write the unclamped image and its render passes to this multi-layer OpenEXR file: the
+    /// environment seen directly ("emission"), direct and indirect diffuse light, light seen
+    /// through mirrors and glass ("specular"), albedo, normal, depth ("Z"), and the direct light
+    /// from each light ("light0", ...).
+    #[structopt(
+        long,
+        parse(from_os_str),
+        requires = "headless",
+        conflicts_with = "mode"
+    )]
+    pub aov_output: Option<PathBuf>,
+
+    /// Diameter of the camera lens, for depth of field. 0 is a pinhole camera.
+    #[structopt(long, default_value = "0")]
+    pub aperture: f32,
+
+    /// Save the accumulated samples to this file every --checkpoint_interval seconds and at the
+    /// end of the render, so that it can be continued with --resume.
+    #[structopt(long, parse(from_os_str), conflicts_with = "frames")]
+    pub checkpoint: Option<PathBuf>,
+
+    /// Seconds between checkpoints.
+    #[structopt(long, default_value = "300")]
+    pub checkpoint_interval: u64,
+
+    /// Limit the light each sample gathers by scattering off the first surface it hits, scaling
+    /// it down so that no channel exceeds this value. This removes fireflies (rare, very bright
+    /// samples, such as caustics through glass) at the cost of bias: the image is darker than
+    /// it should be wherever that light is bright, and it stays darker however many samples are
+    /// taken.
+    #[structopt(long)]
+    pub clamp_indirect: Option<f32>,
+
+    /// Only render the window "x0,y0,x1,y1" of the image, measured from its top-left corner.
+    /// Coordinates are in pixels, or fractions of the image size if they are all at most 1.
+    /// The rest of the image is left black. Pixels are sampled exactly as in the full render.
+    #[structopt(long)]
+    pub crop: Option<CropWindow>,
+
+    /// Write only the --crop window to the output images instead of the full frame.
+    #[structopt(long, requires = "crop")]
+    pub crop_image: bool,
+
+    /// Smooth the noise in the image with an edge-avoiding filter, guided by the albedo, normal,
+    /// and depth of the first surface in each pixel. "final" denoises the finished image;
+    /// "passes" also denoises every pass shown in the window. Files written with --aov_output
+    /// are not denoised.
+    #[structopt(long, conflicts_with = "mode")]
+    pub denoise: Option<DenoiseMode>,
+
+    /// Number of filter iterations used by --denoise, from 1 to 10. Each one doubles the
+    /// filter's reach.
+    #[structopt(long, default_value = "5")]
+    pub denoise_iterations: u32,
+
+    /// Radiance HDR file (.hdr) with an equirectangular map to use as the environment instead
+    /// of the background gradient.
+    #[structopt(long, parse(from_os_str), conflicts_with = "sky")]
+    pub environment: Option<PathBuf>,
+
+    /// Multiplier for the brightness of the environment map.
+    #[structopt(long, default_value = "1")]
+    pub environment_intensity: f32,
+
+    /// Rotation (in degrees) of the environment map around the vertical axis.
+    #[structopt(long, default_value = "0")]
+    pub environment_rotation: f32,
+
+    /// Pixel reconstruction filter. Valid values are "box", "tent", "gaussian", "mitchell",
+    /// and "lanczos".
+    #[structopt(long, default_value = "box")]
+    pub filter: FilterKind,
+
+    /// Radius of the reconstruction filter in pixels. Defaults to a radius suited to the filter.
+    #[structopt(long)]
+    pub filter_radius: Option<f32>,
+
+    /// Rays each sample of --integrator ppm sends from the first diffuse surface it sees, to
+    /// find the light bouncing onto it. 0 estimates that light from the photons on the surface
+    /// instead, which is faster but blotchier.
+    #[structopt(long, default_value = "1")]
+    pub final_gather: u32,
+
+    /// Field of view (in degrees) of the fisheye projection, across the height of the image.
+    #[structopt(long, default_value = "180")]
+    pub fisheye_fov: f32,
+
+    /// Distance from the camera to the plane in focus. Defaults to the distance from
+    /// --look_from to --look_at.
+    #[structopt(long)]
+    pub focus_distance: Option<f32>,
+
+    /// Render this range of frames (inclusive, e.g. "1..120") of the scene's camera animation.
+    /// Each frame is written to --output with the frame number added, e.g. out_0001.png.
+    /// Frames that already exist are skipped.
+    #[structopt(long, requires = "headless")]
+    pub frames: Option<FrameRange>,
+
+    /// Render without opening a window. Requires --output.
+    #[structopt(long)]
+    pub headless: bool,
+
+    /// Hue for the background gradient.
     #[structopt(long, default_value = "205")]
     pub hue: f32,
 
+    /// How the light reaching the camera is found: "path" traces paths from the camera;
+    /// "bdpt" (bidirectional) also traces paths from the point and spot lights and joins them
+    /// up, which finds caustics and light through small openings much sooner. In bdpt, the
+    /// environment is only found by paths from the camera, and light paths only reach pinhole
+    /// perspective cameras. "ppm" (progressive photon mapping) traces --photons photons from
+    /// the point and spot lights and the environment each pass and gathers them from the surfaces
+    /// seen by the camera, which finds caustics through glass much sooner. "mlt" (Metropolis light
+    /// transport) mutates paths from the path tracer, spending more of them where the image is
+    /// bright, which helps with light that only a few paths find. For quick previews, "ao" (ambient
+    /// occlusion) shades surfaces by how much of the sky they can see, and "whitted" lights them
+    /// directly and traces mirrors and glass without randomness. Only "path" can write
+    /// --aov_output, --denoise, or debug --mode views.
+    #[structopt(long, default_value = "path")]
+    pub integrator: Integrator,
+
+    /// Distance between the eyes for stereo rendering, in world units.
+    #[structopt(long, default_value = "0.064")]
+    pub ipd: f32,
+
+    /// Sample the environment directly from diffuse surfaces instead of waiting for scattered
+    /// rays to escape the scene.
+    #[structopt(long)]
+    pub light_sampling: bool,
+
     /// Point at the head of the camera ray.
     #[structopt(long, default_value = "0,0,-1")]
     pub look_at: Vec3, // TODO
@@ -30,9 +188,84 @@ pub struct Config {
     #[structopt(long, default_value = "50", visible_alias = "md")]
     pub max_depth: u8,
 
+    /// Distance at which --mode depth fades to black.
+    #[structopt(long, default_value = "10")]
+    pub max_distance: f32,
+
+    /// The minimum number of samples for each pixel when using adaptive sampling.
+    #[structopt(long, default_value = "4", visible_alias = "mns")]
+    pub min_samples: u8,
+
+    /// What to render: "beauty" (the path traced image), or a view for debugging the scene:
+    /// "normals", "depth", "albedo", "object-id", "uv", or "bounces" (the length of each path).
+    #[structopt(long, default_value = "beauty")]
+    pub mode: RenderMode,
+
+    /// Mutations for each pixel, each pass, of --integrator mlt. A pass with the Metropolis
+    /// integrator costs about as much as this many passes of the path tracer.
+    #[structopt(long, default_value = "16")]
+    pub mutations_per_pixel: u32,
+
     /// The number of sample paths to trace for each output pixel.
     #[structopt(long, default_value = "5", visible_alias = "ns")]
-    pub num_samples: u8,
+    pub num_samples: u32,
+
+    /// Write the rendered image to this PNG file.
+    #[structopt(long, parse(from_os_str))]
+    pub output: Option<PathBuf>,
+
+    /// Horizontal coverage (in degrees) of the equirectangular projection: 360 for a full
+    /// panorama, 180 for VR180.
+    #[structopt(long, default_value = "360")]
+    pub panorama_fov: f32,
+
+    /// Distance from a surface within which --integrator ppm gathers photons, in world units,
+    /// for the first pass. It shrinks with every pass, trading blur for noise.
+    #[structopt(long, default_value = "0.1")]
+    pub photon_radius: f32,
+
+    /// Photons traced from the lights and the environment for each pass of --integrator ppm.
+    #[structopt(long, default_value = "50000")]
+    pub photons: u32,
+
+    /// Fill the window with a single pass of this integrator (e.g. "ao" or "whitted") before
+    /// rendering with --integrator, for a quick look at the scene.
+    #[structopt(long, conflicts_with = "headless")]
+    pub preview: Option<Integrator>,
+
+    /// How to report progress: "text" (a status line on stderr), "json" (a JSON object per
+    /// line on stdout, with the elapsed and remaining seconds and the throughput), or "none".
+    #[structopt(long, default_value = "text")]
+    pub progress: ProgressFormat,
+
+    /// Camera projection. Valid values are "perspective", "orthographic", "fisheye", and
+    /// "equirectangular" (a full 360 degree panorama). A camera in the scene file takes
+    /// precedence over the camera options.
+    #[structopt(long, default_value = "perspective")]
+    pub projection: Projection,
+
+    /// Continue the render saved in --checkpoint, adding passes until there are --num_samples.
+    /// The scene and render settings must not have changed.
+    #[structopt(long, requires = "checkpoint")]
+    pub resume: bool,
+
+    /// Once a path has hit a diffuse surface, blur the directions of its mirror and glass
+    /// bounces by this roughness (0 to 1). Caustics seen by diffuse surfaces become smooth
+    /// patches of light that converge quickly, rather than fireflies. This is biased: the
+    /// caustics are spread out, and mirrors and glass seen in indirect light look rough.
+    #[structopt(long)]
+    pub roughen: Option<f32>,
+
+    /// After a path has hit this many surfaces, randomly end it with a probability that grows
+    /// as its throughput falls, so that dim paths stop early while paths through glass and
+    /// mirrors continue. The light from the paths that go on is scaled up to make up for the
+    /// ones that end, so the image is unbiased. Paths still stop at --max_depth.
+    #[structopt(long)]
+    pub roulette_depth: Option<u8>,
+
+    /// Write a PNG heatmap of the number of samples taken for each pixel to this file.
+    #[structopt(long, parse(from_os_str))]
+    pub sample_heatmap: Option<PathBuf>,
 
     /// Scale at which to display the rendered images.
     /// Valid values are 0 (fit to screen), 1, 2, 4, 8, 16, & 32.
@@ -47,11 +280,64 @@ pub struct Config {
     #[structopt(long, short = "w", default_value = "320", visible_alias = "sw")]
     pub screen_width: usize,
 
+    /// Use a physically based daylight sky (with a sun) instead of the background gradient.
+    #[structopt(long)]
+    pub sky: bool,
+
+    /// Brightness multiplier for the sky and sun.
+    #[structopt(long, default_value = "1")]
+    pub sky_intensity: f32,
+
+    /// Trace light at a few wavelengths (hero wavelength sampling) instead of as RGB, so that
+    /// glass given dispersion coefficients splits white light into colors. Colors in the world
+    /// file are turned into smooth spectra. Only works with --integrator path; the environment
+    /// is only found by rays that escape the scene.
+    #[structopt(
+        long,
+        raw(
+            conflicts_with_all = r#"&["clamp_indirect", "light_sampling", "roughen",
+                                      "roulette_depth", "aov_output", "denoise"]"#
+        )
+    )]
+    pub spectral: bool,
+
+    /// Print statistics about the render (rays traced, intersection tests, and path lengths)
+    /// when it is done, as a "table" or as "json".
+    #[structopt(long)]
+    pub stats: Option<StatsFormat>,
+
+    /// Render a stereo pair, with the eyes arranged "side-by-side" or "over-under" (left eye on
+    /// top). Equirectangular panoramas use omni-directional stereo.
+    #[structopt(long)]
+    pub stereo: Option<StereoLayout>,
+
+    /// Compass direction of the sun in degrees. 0 is straight ahead of the default camera (-z),
+    /// 90 is to its right (+x).
+    #[structopt(long, default_value = "0")]
+    pub sun_azimuth: f32,
+
+    /// Angle of the sun above the horizon in degrees.
+    #[structopt(long, default_value = "45")]
+    pub sun_elevation: f32,
+
+    /// Keep adding passes until this much time has passed (e.g. "90s", "10m", or "1h30m"),
+    /// instead of stopping after --num_samples. The pass in progress is always finished.
+    #[structopt(long)]
+    pub time_limit: Option<TimeLimit>,
+
+    /// Haziness of the sky, from 2 (very clear) to 10 (hazy).
+    #[structopt(long, default_value = "3")]
+    pub turbidity: f32,
+
     /// Angle (in degrees) of the camera fovea on the vertical axis.
     #[structopt(long, default_value = "90")]
     pub vfov: f32,
 
-    /// Write a YAML description of the World to this file before rendering.
+    /// Width of the view, in world units, for the orthographic projection.
+    #[structopt(long, default_value = "2")]
+    pub view_width: f32,
+
+    /// Write a YAML description of the scene (objects and lights) to this file before rendering.
     #[structopt(long = "write_world", short = "o", parse(from_os_str))]
     pub write_world: Option<PathBuf>,
 
@@ -59,11 +345,202 @@ pub struct Config {
     #[structopt(long, conflicts_with = "world_files")]
     pub world: Option<Worlds>, // TODO
 
-    /// YAML files containing world descriptions. Multiple files will be merged.
+    /// YAML files containing scene descriptions: either a list of objects, or a map with
+    /// "objects" and "lights" lists. Multiple files will be merged.
     #[structopt(multiple = true, parse(from_os_str))]
     pub world_files: Vec<PathBuf>,
 }
 
+impl Config {
+    pub fn camera_settings(&self) -> CameraSettings {
+        CameraSettings {
+            projection: self.projection,
+            look_from: self.look_from,
+            look_at: self.look_at,
+            look_up: self.look_up,
+            vfov: self.vfov,
+            view_width: self.view_width,
+            fisheye_fov: self.fisheye_fov,
+            panorama_fov: self.panorama_fov,
+            stereo: self.stereo,
+            ipd: self.ipd,
+            aperture: self.aperture,
+            focus_distance: self.focus_distance,
+        }
+    }
+}
+
+// A window of the image given on the command line as "x0,y0,x1,y1", from the top-left corner of
+// the image. Windows given in fractions of the image size are scaled when converted to pixels.
+#[derive(Debug, Copy, Clone)]
+pub struct CropWindow {
+    pub x0: f32,
+    pub y0: f32,
+    pub x1: f32,
+    pub y1: f32,
+}
+
+impl CropWindow {
+    fn is_normalized(&self) -> bool {
+        self.x0 <= 1.0 && self.y0 <= 1.0 && self.x1 <= 1.0 && self.y1 <= 1.0
+    }
+
+    // The pixels covered by the window in a width x height image, in frame buffer coordinates.
+    pub fn rect(&self, width: usize, height: usize) -> Result<Rect> {
+        let (sx, sy) = if self.is_normalized() {
+            (width as f32, height as f32)
+        } else {
+            (1.0, 1.0)
+        };
+        let x0 = f32::round(self.x0 * sx);
+        let y0 = f32::round(self.y0 * sy);
+        let x1 = f32::round(self.x1 * sx);
+        let y1 = f32::round(self.y1 * sy);
+
+        if x0 < 0.0 || y0 < 0.0 || x1 > width as f32 || y1 > height as f32 {
+            return Err(ErrorKind::InvalidCropWindow(
+                self.to_string(),
+                format!("Must be inside the {}x{} image.", width, height),
+            )
+            .into());
+        }
+        if x0 >= x1 || y0 >= y1 {
+            return Err(ErrorKind::InvalidCropWindow(
+                self.to_string(),
+                "Must cover at least one pixel.".to_string(),
+            )
+            .into());
+        }
+
+        // The frame buffers count rows from the bottom of the image.
+        Ok(Rect {
+            x0: x0 as usize,
+            y0: height - y1 as usize,
+            x1: x1 as usize,
+            y1: height - y0 as usize,
+        })
+    }
+}
+
+impl fmt::Display for CropWindow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.x0, self.y0, self.x1, self.y1)
+    }
+}
+
+impl FromStr for CropWindow {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<CropWindow> {
+        let pieces = s.split(',').collect::<Vec<&str>>();
+        if pieces.len() != 4 {
+            return Err(
+                ErrorKind::ParseError(s.to_string(), "Must be 'x0,y0,x1,y1'.".to_string()).into(),
+            );
+        }
+        Ok(CropWindow {
+            x0: f32::from_str(pieces[0].trim())?,
+            y0: f32::from_str(pieces[1].trim())?,
+            x1: f32::from_str(pieces[2].trim())?,
+            y1: f32::from_str(pieces[3].trim())?,
+        })
+    }
+}
+
+// A render time budget given on the command line as a sequence of numbers with units ("h", "m",
+// or "s"), e.g. "1h30m". A bare number is in seconds.
+#[derive(Debug, Copy, Clone)]
+pub struct TimeLimit(pub Duration);
+
+impl FromStr for TimeLimit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<TimeLimit> {
+        let parse_error = || -> Error {
+            ErrorKind::ParseError(
+                s.to_string(),
+                "Must be a duration like '90s', '10m', or '1h30m'.".to_string(),
+            )
+            .into()
+        };
+
+        let mut seconds = 0.0;
+        let mut number = String::new();
+        for ch in s.trim().chars() {
+            let scale = match ch {
+                'h' => 3600.0,
+                'm' => 60.0,
+                's' => 1.0,
+                _ => {
+                    number.push(ch);
+                    continue;
+                }
+            };
+            if number.is_empty() {
+                return Err(parse_error());
+            }
+            seconds += f64::from_str(&number).map_err(|_| parse_error())? * scale;
+            number.clear();
+        }
+        if !number.is_empty() {
+            seconds += f64::from_str(&number).map_err(|_| parse_error())?;
+        }
+
+        if seconds <= 0.0 {
+            return Err(parse_error());
+        }
+        let duration = Duration::try_from_secs_f64(seconds).map_err(|_| parse_error())?;
+        Ok(TimeLimit(duration))
+    }
+}
+
+// An inclusive range of animation frames.
+#[derive(Debug, Copy, Clone)]
+pub struct FrameRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl FrameRange {
+    pub fn frames(&self) -> impl Iterator<Item = u32> {
+        self.start..=self.end
+    }
+}
+
+impl FromStr for FrameRange {
+    type Err = Error;
+
+    // "start..end", or a single frame number.
+    fn from_str(s: &str) -> Result<FrameRange> {
+        let pieces = s.split("..").collect::<Vec<&str>>();
+        let (start, end) = match pieces.len() {
+            1 => {
+                let frame = u32::from_str(pieces[0].trim())?;
+                (frame, frame)
+            }
+            2 => (
+                u32::from_str(pieces[0].trim())?,
+                u32::from_str(pieces[1].trim())?,
+            ),
+            _ => {
+                return Err(ErrorKind::ParseError(
+                    s.to_string(),
+                    "Must be 'start..end'.".to_string(),
+                )
+                .into())
+            }
+        };
+        if start > end {
+            return Err(ErrorKind::ParseError(
+                s.to_string(),
+                "The first frame must not be after the last.".to_string(),
+            )
+            .into());
+        }
+        Ok(FrameRange { start, end })
+    }
+}
+
 fn num_to_scale(num: usize) -> Result<Scale> {
     match num {
         0 => Ok(Scale::FitScreen),
@@ -85,3 +562,21 @@ fn string_to_scale(s: &str) -> Result<Scale> {
     let num = usize::from_str(s)?;
     num_to_scale(num)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_time_limit() {
+        let seconds = |s| TimeLimit::from_str(s).unwrap().0.as_secs();
+        assert_eq!(90, seconds("90s"));
+        assert_eq!(600, seconds("10m"));
+        assert_eq!(5400, seconds("1h30m"));
+        assert_eq!(45, seconds("45"));
+
+        for bad in &["", "h", "0s", "-5s", "1x", "1e20s", "inf"] {
+            assert!(TimeLimit::from_str(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,188 @@
+use std::f32;
+use std::str::FromStr;
+
+use crate::color::Color;
+use crate::errors::*;
+use crate::hittest::HitTest;
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::stats::{count_ray, RayKind};
+use crate::vec3::Vec3;
+
+// What each pixel shows: the path traced image, or one of the debugging views of the geometry
+// and materials.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RenderMode {
+    Beauty,
+    Normals,
+    Depth,
+    Albedo,
+    ObjectId,
+    Uv,
+    Bounces,
+}
+
+impl FromStr for RenderMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<RenderMode> {
+        match s.to_lowercase().as_str() {
+            "beauty" => Ok(RenderMode::Beauty),
+            "normals" => Ok(RenderMode::Normals),
+            "depth" => Ok(RenderMode::Depth),
+            "albedo" => Ok(RenderMode::Albedo),
+            "object-id" | "objectid" => Ok(RenderMode::ObjectId),
+            "uv" => Ok(RenderMode::Uv),
+            "bounces" => Ok(RenderMode::Bounces),
+            _ => Err(ErrorKind::ParseError(
+                s.to_string(),
+                "Must be 'beauty', 'normals', 'depth', 'albedo', 'object-id', 'uv', or 'bounces'."
+                    .to_string(),
+            )
+            .into()),
+        }
+    }
+}
+
+// The color of the ray for one of the debugging modes. Rays that miss the scene are black.
+//
+// Depth is shaded from white at the camera to black at max_distance. Bounces are counted until
+// the path leaves the scene, is absorbed, or reaches max_depth, and shown from blue (one bounce)
+// to red (ten or more).
+pub fn debug_color(
+    mode: RenderMode,
+    ray: &Ray,
+    scene: &Scene,
+    max_depth: u8,
+    max_distance: f32,
+) -> Result<Vec3> {
+    match mode {
+        RenderMode::Beauty => {
+            return Err(ErrorKind::InvalidOptions(
+                "mode".to_string(),
+                "'beauty' is not a debugging view.".to_string(),
+            )
+            .into())
+        }
+        RenderMode::Bounces => return count_bounces(ray, scene, max_depth),
+        _ => {}
+    }
+
+    count_ray(RayKind::Primary);
+    let hit_record = match scene.hit_test(ray, 0.001, f32::MAX) {
+        Some(hit_record) => hit_record,
+        None => return Ok(Vec3::origin()),
+    };
+
+    Ok(match mode {
+        RenderMode::Normals => 0.5 * (hit_record.normal + Vec3::cartesian(1.0, 1.0, 1.0)),
+        RenderMode::Depth => {
+            let distance = hit_record.t * ray.direction().length();
+            let gray = 1.0 - f32::min(distance / max_distance, 1.0);
+            Vec3::cartesian(gray, gray, gray)
+        }
+        RenderMode::Albedo => hit_record.material.albedo(),
+        RenderMode::ObjectId => {
+            // Successive objects are a golden angle apart in hue, so neighbors stand out.
+            let hue = (hit_record.object as f32 * 137.508) % 360.0;
+            Color::from_hsv(hue, 0.7, 1.0)?.as_vec()
+        }
+        RenderMode::Uv => Vec3::cartesian(hit_record.uv.0, hit_record.uv.1, 0.0),
+        RenderMode::Beauty | RenderMode::Bounces => unreachable!(),
+    })
+}
+
+fn count_bounces(ray: &Ray, scene: &Scene, max_depth: u8) -> Result<Vec3> {
+    let mut ray = Ray::new(*ray.origin(), *ray.direction());
+    let mut bounces = 0;
+    loop {
+        count_ray(if bounces == 0 {
+            RayKind::Primary
+        } else {
+            RayKind::Secondary
+        });
+        let hit_record = match scene.hit_test(&ray, 0.001, f32::MAX) {
+            Some(hit_record) => hit_record,
+            None => break,
+        };
+        bounces += 1;
+        if bounces > u32::from(max_depth) {
+            break;
+        }
+        match hit_record.material.scatter(&ray, &hit_record)? {
+            Some((scattered, _)) => ray = scattered,
+            None => break,
+        }
+    }
+
+    if bounces == 0 {
+        return Ok(Vec3::origin());
+    }
+    let hue = 240.0 * (1.0 - f32::min(bounces as f32 - 1.0, 9.0) / 9.0);
+    Ok(Color::from_hsv(hue, 1.0, 1.0)?.as_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::material::{Lambertian, Metal};
+    use crate::sphere::Sphere;
+
+    // Straight down the -z axis, hitting the front of whatever is centered on it.
+    fn head_on() -> Ray {
+        Ray::new(Vec3::origin(), Vec3::cartesian(0.0, 0.0, -1.0))
+    }
+
+    #[test]
+    fn test_surface_views() {
+        let scene = Scene::from_world(vec![Sphere::new(
+            &Vec3::cartesian(0.0, 0.0, -2.0),
+            0.5,
+            Lambertian::new(Color::new(0.8, 0.1, 0.1).unwrap()),
+        )
+        .unwrap()]);
+        let view = |mode| debug_color(mode, &head_on(), &scene, 50, 3.0).unwrap();
+
+        assert_eq!(Vec3::cartesian(0.5, 0.5, 1.0), view(RenderMode::Normals));
+        assert_eq!(Vec3::cartesian(0.5, 0.5, 0.5), view(RenderMode::Depth));
+        assert_eq!(Vec3::cartesian(0.8, 0.1, 0.1), view(RenderMode::Albedo));
+        assert!(debug_color(RenderMode::Beauty, &head_on(), &scene, 50, 3.0).is_err());
+
+        let miss = Ray::new(Vec3::origin(), Vec3::cartesian(0.0, 1.0, 0.0));
+        let black = Vec3::origin();
+        assert_eq!(
+            black,
+            debug_color(RenderMode::Normals, &miss, &scene, 50, 3.0).unwrap()
+        );
+        assert_eq!(
+            black,
+            debug_color(RenderMode::Bounces, &miss, &scene, 50, 3.0).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_bounces() {
+        let mirror = |z| {
+            let metal = Metal::new(Color::new(0.9, 0.9, 0.9).unwrap());
+            Sphere::new(&Vec3::cartesian(0.0, 0.0, z), 0.5, metal).unwrap()
+        };
+        let bounces = |scene: &Scene, max_depth| {
+            debug_color(RenderMode::Bounces, &head_on(), scene, max_depth, 3.0).unwrap()
+        };
+
+        // One mirror sends the ray straight back out of the scene.
+        let one = Scene::from_world(vec![mirror(-2.0)]);
+        assert_eq!(
+            Color::from_hsv(240.0, 1.0, 1.0).unwrap().as_vec(),
+            bounces(&one, 50)
+        );
+
+        // Two facing mirrors bounce it back and forth until max_depth: four hits with a
+        // max_depth of 3, shown a third of the way from blue to red.
+        let two = Scene::from_world(vec![mirror(-2.0), mirror(2.0)]);
+        assert_eq!(
+            Color::from_hsv(160.0, 1.0, 1.0).unwrap().as_vec(),
+            bounces(&two, 3)
+        );
+    }
+}
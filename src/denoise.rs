@@ -0,0 +1,237 @@
+use std::f32;
+use std::str::FromStr;
+
+use crate::errors::*;
+use crate::fb::IncrementalFrameBuffer;
+use crate::integrator::MISS_DISTANCE;
+use crate::vec3::Vec3;
+
+// When the denoiser runs: only on the finished image, or also on every pass shown in the window.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DenoiseMode {
+    Final,
+    Passes,
+}
+
+impl FromStr for DenoiseMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<DenoiseMode> {
+        match s.to_lowercase().as_str() {
+            "final" => Ok(DenoiseMode::Final),
+            "passes" => Ok(DenoiseMode::Passes),
+            _ => Err(ErrorKind::ParseError(
+                s.to_string(),
+                "Must be 'final' or 'passes'.".to_string(),
+            )
+            .into()),
+        }
+    }
+}
+
+// Per-pixel features of the first surface seen through each pixel, which tell the denoiser
+// where the edges in the image are. Pixels are stored from the top row down.
+pub struct FeatureBuffers {
+    pub albedo: Vec<Vec3>,
+    pub normal: Vec<Vec3>,
+
+    // Distance to the surface, or MISS_DISTANCE where nothing was hit.
+    pub depth: Vec<f32>,
+}
+
+impl FeatureBuffers {
+    // Reads the features from the "albedo.R/G/B", "normal.X/Y/Z", and "Z" channels of ifb, or
+    // returns None if it does not have them.
+    pub fn from_frame_buffer(ifb: &IncrementalFrameBuffer) -> Option<FeatureBuffers> {
+        let vectors = |names: [&str; 3]| -> Option<Vec<Vec3>> {
+            let x = ifb.channel(names[0])?;
+            let y = ifb.channel(names[1])?;
+            let z = ifb.channel(names[2])?;
+            Some(
+                (0..x.len())
+                    .map(|i| Vec3::cartesian(x[i], y[i], z[i]))
+                    .collect(),
+            )
+        };
+        Some(FeatureBuffers {
+            albedo: vectors(["albedo.R", "albedo.G", "albedo.B"])?,
+            normal: vectors(["normal.X", "normal.Y", "normal.Z"])?,
+            depth: ifb.channel("Z")?,
+        })
+    }
+}
+
+// How quickly the weight of a neighbor falls off with its difference from the center pixel.
+const SIGMA_COLOR: f32 = 0.3;
+const SIGMA_NORMAL: f32 = 0.1;
+const SIGMA_DEPTH: f32 = 0.1;
+
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+// Smooths the noise in image with the edge-avoiding a-trous wavelet filter of Dammertz et al.:
+// a 5x5 blur whose taps spread twice as far each iteration, skipping neighbors whose color,
+// normal, or depth differ from the pixel's.
+//
+// The lighting is filtered separately from the albedo, so that surface colors stay sharp.
+pub fn denoise(
+    image: &[Vec3],
+    features: &FeatureBuffers,
+    width: usize,
+    height: usize,
+    iterations: u32,
+) -> Vec<Vec3> {
+    let mut lighting = image
+        .iter()
+        .zip(&features.albedo)
+        .map(|(color, albedo)| demodulate(color, albedo))
+        .collect::<Vec<_>>();
+
+    for iteration in 0..iterations {
+        let step = 1 << iteration;
+        // Later iterations see smoother input, so they tolerate smaller color differences.
+        let sigma_color = SIGMA_COLOR / 2f32.powi(iteration as i32);
+
+        let mut filtered = Vec::with_capacity(lighting.len());
+        for y in 0..height {
+            for x in 0..width {
+                let p = y * width + x;
+                let mut sum = Vec3::origin();
+                let mut total_weight = 0.0;
+                for (j, ky) in KERNEL.iter().enumerate() {
+                    let qy = y as isize + (j as isize - 2) * step;
+                    if qy < 0 || qy >= height as isize {
+                        continue;
+                    }
+                    for (i, kx) in KERNEL.iter().enumerate() {
+                        let qx = x as isize + (i as isize - 2) * step;
+                        if qx < 0 || qx >= width as isize {
+                            continue;
+                        }
+                        let q = qy as usize * width + qx as usize;
+                        let weight = kx
+                            * ky
+                            * edge_weight(&lighting, features, p, q, sigma_color, step as f32);
+                        sum = sum + weight * lighting[q];
+                        total_weight += weight;
+                    }
+                }
+                // The center tap always has a weight, so total_weight is never zero.
+                filtered.push(sum / total_weight);
+            }
+        }
+        lighting = filtered;
+    }
+
+    lighting
+        .iter()
+        .zip(&features.albedo)
+        .map(|(light, albedo)| remodulate(light, albedo))
+        .collect()
+}
+
+fn edge_weight(
+    lighting: &[Vec3],
+    features: &FeatureBuffers,
+    p: usize,
+    q: usize,
+    sigma_color: f32,
+    step: f32,
+) -> f32 {
+    // Compare colors after compressing their range, so that bright pixels are not isolated.
+    let compress = |c: &Vec3| {
+        Vec3::cartesian(
+            c.x() / (1.0 + c.x()),
+            c.y() / (1.0 + c.y()),
+            c.z() / (1.0 + c.z()),
+        )
+    };
+    let color_distance = (compress(&lighting[p]) - compress(&lighting[q])).squared_length();
+    let normal_distance =
+        (features.normal[p] - features.normal[q]).squared_length() / (step * step);
+
+    let (dp, dq) = (features.depth[p], features.depth[q]);
+    let (hit_p, hit_q) = (dp < MISS_DISTANCE, dq < MISS_DISTANCE);
+    let depth_weight = if hit_p && hit_q {
+        f32::exp(-(dp - dq).abs() / (SIGMA_DEPTH * f32::max(dp, 1.0) * step))
+    } else if hit_p == hit_q {
+        1.0
+    } else {
+        0.0
+    };
+
+    f32::exp(-color_distance / (sigma_color * sigma_color))
+        * f32::exp(-normal_distance / SIGMA_NORMAL)
+        * depth_weight
+}
+
+// Divides the albedo out of color, leaving the light arriving at the surface. Channels with no
+// albedo (including pixels where nothing was hit) are left as they are.
+fn demodulate(color: &Vec3, albedo: &Vec3) -> Vec3 {
+    let channel = |c: f32, a: f32| if a > 1.0e-3 { c / a } else { c };
+    Vec3::cartesian(
+        channel(color.x(), albedo.x()),
+        channel(color.y(), albedo.y()),
+        channel(color.z(), albedo.z()),
+    )
+}
+
+fn remodulate(light: &Vec3, albedo: &Vec3) -> Vec3 {
+    let channel = |l: f32, a: f32| if a > 1.0e-3 { l * a } else { l };
+    Vec3::cartesian(
+        channel(light.x(), albedo.x()),
+        channel(light.y(), albedo.y()),
+        channel(light.z(), albedo.z()),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::unit_random::{seed_unit_random, unit_random};
+
+    #[test]
+    fn test_denoise() {
+        // Two noisy gray halves, facing different ways, with a dark left half.
+        let (width, height) = (16, 8);
+        seed_unit_random(1);
+        let mut image = vec![];
+        let mut normal = vec![];
+        for _ in 0..height {
+            for x in 0..width {
+                let base = if x < width / 2 { 0.2 } else { 0.8 };
+                let value = base + 0.2 * (unit_random() - 0.5);
+                image.push(Vec3::cartesian(value, value, value));
+                normal.push(if x < width / 2 {
+                    Vec3::cartesian(1.0, 0.0, 0.0)
+                } else {
+                    Vec3::cartesian(0.0, 0.0, 1.0)
+                });
+            }
+        }
+        let features = FeatureBuffers {
+            albedo: vec![Vec3::cartesian(1.0, 1.0, 1.0); width * height],
+            normal,
+            depth: vec![1.0; width * height],
+        };
+
+        let denoised = denoise(&image, &features, width, height, 3);
+
+        let deviation = |image: &[Vec3], x0: usize, x1: usize, mean: f32| {
+            let mut sum = 0.0;
+            for y in 0..height {
+                for x in x0..x1 {
+                    sum += (image[y * width + x].x() - mean).powi(2);
+                }
+            }
+            f32::sqrt(sum / ((x1 - x0) * height) as f32)
+        };
+        // The noise is reduced...
+        assert!(deviation(&denoised, 0, 8, 0.2) < 0.5 * deviation(&image, 0, 8, 0.2));
+        assert!(deviation(&denoised, 8, 16, 0.8) < 0.5 * deviation(&image, 8, 16, 0.8));
+        // ...without blurring across the edge between the halves.
+        for y in 0..height {
+            assert!(denoised[y * width + 7].x() < 0.35);
+            assert!(denoised[y * width + 8].x() > 0.65);
+        }
+    }
+}
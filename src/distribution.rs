@@ -0,0 +1,116 @@
+// Piecewise-constant distributions used to importance sample tabulated functions such as
+// environment maps.
+
+pub struct Distribution1D {
+    func: Vec<f32>,
+    cdf: Vec<f32>,
+    integral: f32,
+}
+
+impl Distribution1D {
+    pub fn new(func: Vec<f32>) -> Distribution1D {
+        let n = func.len();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 0..n {
+            cdf[i + 1] = cdf[i] + func[i] / n as f32;
+        }
+
+        let integral = cdf[n];
+        for (i, value) in cdf.iter_mut().enumerate() {
+            // A function that is zero everywhere gets a uniform distribution.
+            *value = if integral == 0.0 {
+                i as f32 / n as f32
+            } else {
+                *value / integral
+            };
+        }
+
+        Distribution1D {
+            func,
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    pub fn integral(&self) -> f32 {
+        self.integral
+    }
+
+    // Maps u in [0, 1) to a value in [0, 1) distributed proportionally to the function.
+    // Returns the value, its pdf, and the index of the segment containing it.
+    pub fn sample(&self, u: f32) -> (f32, f32, usize) {
+        // The last segment whose start is <= u. This skips segments with zero width.
+        let offset = self
+            .cdf
+            .partition_point(|value| *value <= u)
+            .saturating_sub(1);
+        let offset = usize::min(offset, self.count() - 1);
+
+        let mut du = u - self.cdf[offset];
+        let width = self.cdf[offset + 1] - self.cdf[offset];
+        if width > 0.0 {
+            du /= width;
+        }
+
+        let value = (offset as f32 + du) / self.count() as f32;
+        (value, self.pdf(offset), offset)
+    }
+
+    pub fn pdf(&self, offset: usize) -> f32 {
+        if self.integral == 0.0 {
+            1.0
+        } else {
+            self.func[offset] / self.integral
+        }
+    }
+}
+
+// A distribution over [0, 1)^2 built from a row-major table of `width * height` values.
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(func: &[f32], width: usize, height: usize) -> Distribution2D {
+        let conditional = func
+            .chunks(width)
+            .take(height)
+            .map(|row| Distribution1D::new(row.to_vec()))
+            .collect::<Vec<_>>();
+        let marginal =
+            Distribution1D::new(conditional.iter().map(|d| d.integral()).collect::<Vec<_>>());
+        Distribution2D {
+            conditional,
+            marginal,
+        }
+    }
+
+    // Returns the sampled (u, v) and its pdf.
+    pub fn sample(&self, u0: f32, u1: f32) -> ((f32, f32), f32) {
+        let (v, pdf_v, row) = self.marginal.sample(u1);
+        let (u, pdf_u, _) = self.conditional[row].sample(u0);
+        ((u, v), pdf_u * pdf_v)
+    }
+
+    pub fn pdf(&self, u: f32, v: f32) -> f32 {
+        let row = usize::min(
+            (v * self.marginal.count() as f32) as usize,
+            self.marginal.count() - 1,
+        );
+        let conditional = &self.conditional[row];
+        let column = usize::min(
+            (u * conditional.count() as f32) as usize,
+            conditional.count() - 1,
+        );
+        if self.marginal.integral() == 0.0 {
+            1.0
+        } else {
+            conditional.func[column] / self.marginal.integral()
+        }
+    }
+}
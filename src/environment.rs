@@ -0,0 +1,213 @@
+use std::f32;
+use std::path::Path;
+
+use crate::color::{gradient, Color};
+use crate::distribution::Distribution2D;
+use crate::errors::*;
+use crate::hdr::HdrImage;
+use crate::unit_random::unit_random;
+use crate::util::uniform_sphere;
+use crate::vec3::Vec3;
+
+pub struct EnvironmentSample {
+    // Unit vector pointing away from the scene, towards the environment.
+    pub direction: Vec3,
+    pub radiance: Vec3,
+    // Probability density with respect to solid angle.
+    pub pdf: f32,
+}
+
+// Radiance arriving from infinitely far away, seen by every ray that leaves the scene.
+pub trait Environment {
+    // direction must be a unit vector.
+    fn radiance(&self, direction: &Vec3) -> Vec3;
+
+    // Choose a direction for light sampling. The default samples the sphere uniformly.
+    fn sample(&self) -> Option<EnvironmentSample> {
+        let direction = uniform_sphere(unit_random(), unit_random());
+        Some(EnvironmentSample {
+            direction,
+            radiance: self.radiance(&direction),
+            pdf: 1.0 / (4.0 * f32::consts::PI),
+        })
+    }
+
+    // The pdf with which sample() would choose direction.
+    fn pdf(&self, _direction: &Vec3) -> f32 {
+        1.0 / (4.0 * f32::consts::PI)
+    }
+}
+
+// Blends from white at the horizon (and below) to a color with the given hue straight up.
+pub struct Gradient {
+    background: Color,
+}
+
+impl Gradient {
+    pub fn new(hue: f32) -> Result<Gradient> {
+        Ok(Gradient {
+            background: Color::from_hsv(hue, 0.5, 1.0)?,
+        })
+    }
+}
+
+impl Environment for Gradient {
+    fn radiance(&self, direction: &Vec3) -> Vec3 {
+        let t = 0.5 * (direction.y() + 1.0);
+        gradient(t, &Color::white(), &self.background).as_vec()
+    }
+}
+
+// An equirectangular (latitude/longitude) environment map. The center of the image is in the
+// -z direction, the top row is straight up.
+pub struct EnvironmentMap {
+    image: HdrImage,
+    rotation: f32, // radians about the y axis
+    intensity: f32,
+    distribution: Distribution2D,
+}
+
+impl EnvironmentMap {
+    pub fn open(path: &Path, rotation: f32, intensity: f32) -> Result<EnvironmentMap> {
+        Ok(EnvironmentMap::new(
+            HdrImage::open(path)?,
+            rotation,
+            intensity,
+        ))
+    }
+
+    // rotation is in degrees.
+    pub fn new(image: HdrImage, rotation: f32, intensity: f32) -> EnvironmentMap {
+        // Weight each pixel by its luminance and by the solid angle it covers.
+        let height = image.height();
+        let func = image
+            .pixels()
+            .iter()
+            .enumerate()
+            .map(|(i, pixel)| {
+                let theta = f32::consts::PI * ((i / image.width()) as f32 + 0.5) / height as f32;
+                Color::luminance_of(pixel.x(), pixel.y(), pixel.z()) * f32::sin(theta)
+            })
+            .collect::<Vec<_>>();
+        let distribution = Distribution2D::new(&func, image.width(), height);
+
+        EnvironmentMap {
+            image,
+            rotation: rotation.to_radians(),
+            intensity,
+            distribution,
+        }
+    }
+
+    fn direction_to_uv(&self, direction: &Vec3) -> (f32, f32) {
+        let phi = f32::atan2(direction.x(), -direction.z()) - self.rotation;
+        let u = (phi / (2.0 * f32::consts::PI) + 0.5).rem_euclid(1.0);
+        // atan2 keeps its precision near the poles, unlike acos.
+        let horizontal = f32::sqrt(direction.x() * direction.x() + direction.z() * direction.z());
+        let v = f32::atan2(horizontal, direction.y()) / f32::consts::PI;
+        (u, v)
+    }
+
+    fn uv_to_direction(&self, u: f32, v: f32) -> Vec3 {
+        let phi = 2.0 * f32::consts::PI * (u - 0.5) + self.rotation;
+        let theta = f32::consts::PI * v;
+        Vec3::cartesian(
+            f32::sin(theta) * f32::sin(phi),
+            f32::cos(theta),
+            -f32::sin(theta) * f32::cos(phi),
+        )
+    }
+
+    // Converts a pdf over the image's (u, v) square to one over solid angle.
+    fn solid_angle_pdf(uv_pdf: f32, v: f32) -> f32 {
+        let sin_theta = f32::sin(f32::consts::PI * v);
+        if sin_theta <= 0.0 {
+            0.0
+        } else {
+            uv_pdf / (2.0 * f32::consts::PI * f32::consts::PI * sin_theta)
+        }
+    }
+}
+
+impl Environment for EnvironmentMap {
+    fn radiance(&self, direction: &Vec3) -> Vec3 {
+        let (u, v) = self.direction_to_uv(direction);
+        let x = usize::min(
+            (u * self.image.width() as f32) as usize,
+            self.image.width() - 1,
+        );
+        let y = usize::min(
+            (v * self.image.height() as f32) as usize,
+            self.image.height() - 1,
+        );
+        self.intensity * self.image.pixel(x, y)
+    }
+
+    // Samples directions proportionally to the luminance of the map.
+    fn sample(&self) -> Option<EnvironmentSample> {
+        let ((u, v), uv_pdf) = self.distribution.sample(unit_random(), unit_random());
+        let pdf = EnvironmentMap::solid_angle_pdf(uv_pdf, v);
+        if pdf <= 0.0 {
+            return None;
+        }
+
+        let direction = self.uv_to_direction(u, v);
+        Some(EnvironmentSample {
+            direction,
+            radiance: self.radiance(&direction),
+            pdf,
+        })
+    }
+
+    fn pdf(&self, direction: &Vec3) -> f32 {
+        let (u, v) = self.direction_to_uv(direction);
+        EnvironmentMap::solid_angle_pdf(self.distribution.pdf(u, v), v)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A 4x2 map, uncompressed, with a single bright pixel.
+    fn test_map() -> EnvironmentMap {
+        let mut data = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 2 +X 4\n".to_vec();
+        for i in 0..8 {
+            let mantissa = if i == 5 { 255 } else { 16 };
+            data.extend(&[mantissa, mantissa, mantissa, 136]);
+        }
+        EnvironmentMap::new(HdrImage::read(&data[..]).unwrap(), 30.0, 2.0)
+    }
+
+    #[test]
+    fn test_read_hdr() {
+        let map = test_map();
+        assert_eq!(4, map.image.width());
+        assert_eq!(2, map.image.height());
+        assert_eq!(Vec3::cartesian(255.0, 255.0, 255.0), *map.image.pixel(1, 1));
+    }
+
+    #[test]
+    fn test_read_hdr_bad_size() {
+        for size in &[
+            "-Y 1 +X 0",
+            "-Y 0 +X 4",
+            "-Y 100000 +X 100000",
+            "-Y 9999999999 +X 9999999999",
+        ] {
+            let data = format!("#?RADIANCE\n\n{}\n", size);
+            assert!(HdrImage::read(data.as_bytes()).is_err(), "{}", size);
+        }
+    }
+
+    #[test]
+    fn test_sample_pdf() {
+        let map = test_map();
+        for _ in 0..100 {
+            let sample = map.sample().unwrap();
+            let pdf = map.pdf(&sample.direction);
+            assert!((sample.pdf - pdf).abs() / pdf < 1.0e-3);
+            assert_eq!(sample.radiance, map.radiance(&sample.direction));
+        }
+    }
+}
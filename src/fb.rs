@@ -1,22 +1,151 @@
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+
+use exr::prelude::{
+    f16, AnyChannel, AnyChannels, Encoding, FlatSamples, Image, Layer, LayerAttributes, SmallVec,
+    WritableImage,
+};
+
 use crate::color::Color;
 use crate::errors::*;
+use crate::filter::{Filter, FilterKind};
+use crate::vec3::Vec3;
 
 pub struct IncrementalFrameBuffer {
+    // Filter-weighted sum of the samples splatted onto each pixel, and the sum of those weights.
     buffer: Vec<f64>,
+    weights: Vec<f64>,
+
+    // Unweighted statistics about the samples taken in each pixel. Used for adaptive sampling.
+    luminances: Vec<f64>,
+    squares: Vec<f64>,
+    counts: Vec<u32>,
+
+    // Extra channels, such as render passes, named like "albedo.R". Their samples are weighted
+    // like the image's, and share its weight sums.
+    channel_names: Vec<String>,
+    channels: Vec<f64>,
+
+    // Light traced from the lights to the camera by the bidirectional integrator, summed over
+    // all of the light paths traced for the whole image, and the number of those paths.
+    light_image: Vec<f64>,
+    light_paths: u64,
+
+    // Number of complete passes over the image.
+    passes: u32,
+
+    filter: Filter,
     width: usize,
     height: usize,
 }
 
 impl IncrementalFrameBuffer {
     pub fn new(width: usize, height: usize) -> Result<Self> {
+        IncrementalFrameBuffer::with_filter(
+            width,
+            height,
+            Filter::with_default_radius(FilterKind::Box),
+        )
+    }
+
+    pub fn with_filter(width: usize, height: usize, filter: Filter) -> Result<Self> {
+        IncrementalFrameBuffer::with_channels(width, height, filter, vec![])
+    }
+
+    pub fn with_channels(
+        width: usize,
+        height: usize,
+        filter: Filter,
+        channel_names: Vec<String>,
+    ) -> Result<Self> {
+        let channels = vec![0.0; height * width * channel_names.len()];
         let buffer = vec![0.0; height * width * 3];
+        let weights = vec![0.0; height * width];
+        let luminances = vec![0.0; height * width];
+        let squares = vec![0.0; height * width];
+        let counts = vec![0; height * width];
+        let light_image = vec![0.0; height * width * 3];
         Ok(IncrementalFrameBuffer {
             buffer,
+            weights,
+            luminances,
+            squares,
+            counts,
+            channel_names,
+            channels,
+            light_image,
+            light_paths: 0,
+            passes: 0,
+            filter,
             width,
             height,
         })
     }
 
+    // Reads the sums saved by write_sums. The filter is not saved, so it must be the one the
+    // sums were accumulated with.
+    pub(crate) fn read_sums<R: Read>(reader: &mut R, filter: Filter) -> Result<Self> {
+        let width = read_u32(reader)? as usize;
+        let height = read_u32(reader)? as usize;
+        let passes = read_u32(reader)?;
+        let mut channel_names = vec![];
+        for _ in 0..read_u32(reader)? {
+            let mut name = vec![0u8; read_u32(reader)? as usize];
+            reader.read_exact(&mut name)?;
+            channel_names.push(String::from_utf8_lossy(&name).into_owned());
+        }
+        let mut ifb = IncrementalFrameBuffer::with_channels(width, height, filter, channel_names)?;
+        ifb.passes = passes;
+        for value in ifb.buffer.iter_mut().chain(ifb.weights.iter_mut()) {
+            *value = read_f64(reader)?;
+        }
+        for value in ifb.luminances.iter_mut().chain(ifb.squares.iter_mut()) {
+            *value = read_f64(reader)?;
+        }
+        for value in ifb.channels.iter_mut() {
+            *value = read_f64(reader)?;
+        }
+        for count in ifb.counts.iter_mut() {
+            *count = read_u32(reader)?;
+        }
+        let mut light_paths = [0u8; 8];
+        reader.read_exact(&mut light_paths)?;
+        ifb.light_paths = u64::from_le_bytes(light_paths);
+        for value in ifb.light_image.iter_mut() {
+            *value = read_f64(reader)?;
+        }
+        Ok(ifb)
+    }
+
+    pub(crate) fn write_sums<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&(self.width as u32).to_le_bytes())?;
+        writer.write_all(&(self.height as u32).to_le_bytes())?;
+        writer.write_all(&self.passes.to_le_bytes())?;
+        writer.write_all(&(self.channel_names.len() as u32).to_le_bytes())?;
+        for name in &self.channel_names {
+            writer.write_all(&(name.len() as u32).to_le_bytes())?;
+            writer.write_all(name.as_bytes())?;
+        }
+        for value in self.buffer.iter().chain(self.weights.iter()) {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        for value in self.luminances.iter().chain(self.squares.iter()) {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        for value in &self.channels {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        for count in &self.counts {
+            writer.write_all(&count.to_le_bytes())?;
+        }
+        writer.write_all(&self.light_paths.to_le_bytes())?;
+        for value in &self.light_image {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
     pub fn height(&self) -> usize {
         self.height
     }
@@ -25,25 +154,314 @@ impl IncrementalFrameBuffer {
         self.width
     }
 
-    pub fn set(&mut self, x: usize, y: usize, color: Color) {
-        let start_index = (self.height - y - 1) * 3 * self.width + x * 3;
-        self.buffer[start_index] += f64::from(color.r);
-        self.buffer[start_index + 1] += f64::from(color.g);
-        self.buffer[start_index + 2] += f64::from(color.b);
-    }
-
-    pub fn copy_to_fb(&mut self, n: u32, fb: &mut FrameBuffer) {
-        let div = f64::from(n) + 1.0;
-        let i = self.buffer.chunks(3).map(|chunk| {
-            let r = chunk[0] / div;
-            let g = chunk[1] / div;
-            let b = chunk[2] / div;
-            u32::from(Color::new(r as f32, g as f32, b as f32).unwrap())
+    pub fn passes(&self) -> u32 {
+        self.passes
+    }
+
+    // Called after every pixel has had a chance to take another sample.
+    pub fn end_pass(&mut self) {
+        self.passes += 1;
+    }
+
+    pub fn filter(&self) -> &Filter {
+        &self.filter
+    }
+
+    pub fn channel_names(&self) -> &[String] {
+        &self.channel_names
+    }
+
+    fn pixel_index(&self, x: usize, y: usize) -> usize {
+        (self.height - y - 1) * self.width + x
+    }
+
+    // Adds a sample to pixel (x, y) only, ignoring the filter.
+    pub fn set(&mut self, x: usize, y: usize, radiance: &Vec3) {
+        self.accumulate(x, y, 1.0, radiance, &[]);
+        self.record(x, y, radiance);
+    }
+
+    // Adds a sample taken at raster position (px, py) to every pixel within the filter's radius.
+    // Pixel (x, y) covers [x, x + 1) x [y, y + 1).
+    pub fn splat(&mut self, px: f32, py: f32, radiance: &Vec3) {
+        self.splat_channels(px, py, radiance, &[]);
+    }
+
+    // Like splat, also adding a value for each of the extra channels (or none at all).
+    pub fn splat_channels(&mut self, px: f32, py: f32, radiance: &Vec3, values: &[f32]) {
+        let radius = self.filter.radius();
+        let x0 = f32::max(f32::ceil(px - 0.5 - radius), 0.0) as usize;
+        let y0 = f32::max(f32::ceil(py - 0.5 - radius), 0.0) as usize;
+        let x1 = f32::min(f32::floor(px - 0.5 + radius), self.width as f32 - 1.0);
+        let y1 = f32::min(f32::floor(py - 0.5 + radius), self.height as f32 - 1.0);
+
+        if x1 >= 0.0 && y1 >= 0.0 {
+            for y in y0..=y1 as usize {
+                for x in x0..=x1 as usize {
+                    let weight = self.filter.weight(x as f32 + 0.5 - px, y as f32 + 0.5 - py);
+                    if weight != 0.0 {
+                        self.accumulate(x, y, f64::from(weight), radiance, values);
+                    }
+                }
+            }
+        }
+
+        let x = usize::min(px as usize, self.width - 1);
+        let y = usize::min(py as usize, self.height - 1);
+        self.record(x, y, radiance);
+    }
+
+    fn accumulate(&mut self, x: usize, y: usize, weight: f64, radiance: &Vec3, values: &[f32]) {
+        let index = self.pixel_index(x, y);
+        let start = index * self.channel_names.len();
+        for (sum, value) in self.channels[start..].iter_mut().zip(values) {
+            *sum += weight * f64::from(*value);
+        }
+        let start_index = index * 3;
+        self.buffer[start_index] += weight * f64::from(radiance.x());
+        self.buffer[start_index + 1] += weight * f64::from(radiance.y());
+        self.buffer[start_index + 2] += weight * f64::from(radiance.z());
+        self.weights[index] += weight;
+    }
+
+    // Adds light that reached the camera at raster position (px, py) from a light path.
+    pub fn add_light(&mut self, px: f32, py: f32, radiance: &Vec3) {
+        if px < 0.0 || py < 0.0 {
+            return;
+        }
+        let x = usize::min(px as usize, self.width - 1);
+        let y = usize::min(py as usize, self.height - 1);
+        let start_index = self.pixel_index(x, y) * 3;
+        self.light_image[start_index] += f64::from(radiance.x());
+        self.light_image[start_index + 1] += f64::from(radiance.y());
+        self.light_image[start_index + 2] += f64::from(radiance.z());
+    }
+
+    // Counts light paths traced for the image, whether or not they reached the camera.
+    pub fn add_light_paths(&mut self, count: u64) {
+        self.light_paths += count;
+    }
+
+    // The color of the pixel at index: the mean of its samples, plus any light traced to it
+    // from the lights. Each light path could have reached any pixel, so the light traced to a
+    // pixel is scaled by the number of pixels over the number of paths.
+    fn color_at(&self, index: usize) -> [f64; 3] {
+        let weight = self.weights[index];
+        let mut color = [0.0; 3];
+        // Filters with negative lobes can leave a pixel with a tiny or negative total weight.
+        if weight > 1.0e-6 {
+            for (c, value) in color.iter_mut().enumerate() {
+                *value = self.buffer[index * 3 + c] / weight;
+            }
+        }
+        if self.light_paths > 0 {
+            let scale = (self.width * self.height) as f64 / self.light_paths as f64;
+            for (c, value) in color.iter_mut().enumerate() {
+                *value += self.light_image[index * 3 + c] * scale;
+            }
+        }
+        color
+    }
+
+    fn record(&mut self, x: usize, y: usize, radiance: &Vec3) {
+        let index = self.pixel_index(x, y);
+        let lum = f64::from(Color::luminance_of(
+            radiance.x(),
+            radiance.y(),
+            radiance.z(),
+        ));
+        self.luminances[index] += lum;
+        self.squares[index] += lum * lum;
+        self.counts[index] += 1;
+    }
+
+    pub fn count(&self, x: usize, y: usize) -> u32 {
+        self.counts[self.pixel_index(x, y)]
+    }
+
+    // Relative standard error of the mean luminance of the pixel, or None if fewer than two
+    // samples have been added.
+    pub fn relative_error(&self, x: usize, y: usize) -> Option<f64> {
+        let index = self.pixel_index(x, y);
+        let n = f64::from(self.counts[index]);
+        if n < 2.0 {
+            return None;
+        }
+
+        let sum = self.luminances[index];
+        let mean = sum / n;
+        let variance = f64::max((self.squares[index] - sum * mean) / (n - 1.0), 0.0);
+        let std_error = f64::sqrt(variance / n);
+
+        // Keep very dark pixels from demanding an infinite number of samples.
+        Some(std_error / f64::max(mean, 1.0e-3))
+    }
+
+    // A pixel is converged once it has at least min_samples and its relative error is below
+    // max_error.
+    pub fn converged(&self, x: usize, y: usize, max_error: f64, min_samples: u32) -> bool {
+        self.count(x, y) >= min_samples
+            && self
+                .relative_error(x, y)
+                .is_some_and(|error| error <= max_error)
+    }
+
+    pub fn copy_to_fb(&self, fb: &mut FrameBuffer) {
+        let i = (0..self.weights.len()).map(|index| {
+            // Negative filter lobes can push a channel out of range.
+            let channel = |c: f64| c.clamp(0.0, 1.0) as f32;
+            let color = self.color_at(index);
+            u32::from(Color::new(channel(color[0]), channel(color[1]), channel(color[2])).unwrap())
         });
 
         fb.buffer_mut().clear();
         fb.buffer_mut().extend(i);
     }
+
+    // The unclamped image, from the top row down. Pixels with no samples are black, apart from
+    // any light traced to them.
+    pub fn image(&self) -> Vec<Vec3> {
+        (0..self.weights.len())
+            .map(|index| {
+                let color = self.color_at(index);
+                Vec3::cartesian(color[0] as f32, color[1] as f32, color[2] as f32)
+            })
+            .collect()
+    }
+
+    // The values of the extra channel called name, from the top row down, or None if there is
+    // no such channel.
+    pub fn channel(&self, name: &str) -> Option<Vec<f32>> {
+        let c = self.channel_names.iter().position(|n| n == name)?;
+        let stride = self.channel_names.len();
+        Some(
+            self.weights
+                .iter()
+                .enumerate()
+                .map(|(index, weight)| {
+                    if *weight <= 1.0e-6 {
+                        0.0
+                    } else {
+                        (self.channels[index * stride + c] / weight) as f32
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    // Shows the number of samples taken for each pixel, from blue (fewest) to red (most).
+    pub fn copy_heatmap_to_fb(&self, fb: &mut FrameBuffer) {
+        let max = f32::max(*self.counts.iter().max().unwrap_or(&0) as f32, 1.0);
+        let i = self.counts.iter().map(|count| {
+            let hue = 240.0 * (1.0 - *count as f32 / max);
+            u32::from(Color::from_hsv(hue, 1.0, 1.0).unwrap())
+        });
+
+        fb.buffer_mut().clear();
+        fb.buffer_mut().extend(i);
+    }
+
+    // Writes the unclamped image (as R, G, and B) and the extra channels to an OpenEXR file,
+    // as half floats unless full_float is set.
+    pub fn write_exr(&self, path: &Path, full_float: bool) -> Result<()> {
+        let pixels = self.width * self.height;
+        let mut values = vec![vec![0.0; pixels]; 3 + self.channel_names.len()];
+        for index in 0..pixels {
+            let color = self.color_at(index);
+            for (c, channel) in values.iter_mut().enumerate().take(3) {
+                channel[index] = color[c] as f32;
+            }
+            let weight = self.weights[index];
+            if weight <= 1.0e-6 {
+                continue;
+            }
+            let start = index * self.channel_names.len();
+            for (c, channel) in values.iter_mut().skip(3).enumerate() {
+                channel[index] = (self.channels[start + c] / weight) as f32;
+            }
+        }
+
+        let names = ["R", "G", "B"]
+            .iter()
+            .map(|name| name.to_string())
+            .chain(self.channel_names.iter().cloned());
+        let channels = names
+            .zip(values)
+            .map(|(name, values)| {
+                let samples = if full_float {
+                    FlatSamples::F32(values)
+                } else {
+                    FlatSamples::F16(values.into_iter().map(f16::from_f32).collect())
+                };
+                AnyChannel::new(name.as_str(), samples)
+            })
+            .collect();
+
+        // Pixels are stored from the top row down, as OpenEXR expects.
+        let layer = Layer::new(
+            (self.width, self.height),
+            LayerAttributes::default(),
+            Encoding::FAST_LOSSLESS,
+            AnyChannels::sort(SmallVec::from_vec(channels)),
+        );
+        Image::from_layer(layer).write().to_file(path)?;
+        Ok(())
+    }
+}
+
+// A rectangle of pixels, [x0, x1) x [y0, y1), with y increasing up the image like the
+// frame buffers.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Rect {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+impl Rect {
+    pub fn full(width: usize, height: usize) -> Rect {
+        Rect {
+            x0: 0,
+            y0: 0,
+            x1: width,
+            y1: height,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.x1 - self.x0
+    }
+
+    pub fn height(&self) -> usize {
+        self.y1 - self.y0
+    }
+
+    pub fn contains(&self, x: usize, y: usize) -> bool {
+        x >= self.x0 && x < self.x1 && y >= self.y0 && y < self.y1
+    }
+
+    // The rect grown by margin pixels on every side, without leaving a width x height image.
+    pub fn expand(&self, margin: usize, width: usize, height: usize) -> Rect {
+        Rect {
+            x0: self.x0.saturating_sub(margin),
+            y0: self.y0.saturating_sub(margin),
+            x1: usize::min(self.x1 + margin, width),
+            y1: usize::min(self.y1 + margin, height),
+        }
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_f64<R: Read>(reader: &mut R) -> Result<f64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
 }
 
 pub struct FrameBuffer {
@@ -78,7 +496,167 @@ impl FrameBuffer {
         &mut self.buffer
     }
 
+    fn index(&self, x: usize, y: usize) -> usize {
+        (self.height - y - 1) * self.width + x
+    }
+
     pub fn set(&mut self, x: usize, y: usize, color: Color) {
-        self.buffer[(self.height - y - 1) * self.width + x] = color.into();
+        let index = self.index(x, y);
+        self.buffer[index] = color.into();
+    }
+
+    // Fills the frame buffer from image, which is stored from the top row down, clamping each
+    // channel to [0, 1].
+    pub fn copy_from_image(&mut self, image: &[Vec3]) {
+        let i = image.iter().map(|pixel| {
+            let channel = |c: f32| c.clamp(0.0, 1.0);
+            u32::from(
+                Color::new(channel(pixel.x()), channel(pixel.y()), channel(pixel.z())).unwrap(),
+            )
+        });
+
+        self.buffer.clear();
+        self.buffer.extend(i);
+    }
+
+    // A new frame buffer holding only the pixels inside rect.
+    pub fn crop(&self, rect: &Rect) -> Result<FrameBuffer> {
+        let mut cropped = FrameBuffer::new(rect.width(), rect.height())?;
+        for y in rect.y0..rect.y1 {
+            for x in rect.x0..rect.x1 {
+                let index = cropped.index(x - rect.x0, y - rect.y0);
+                cropped.buffer[index] = self.buffer[self.index(x, y)];
+            }
+        }
+        Ok(cropped)
+    }
+
+    // Sets every pixel outside rect to black.
+    pub fn clear_outside(&mut self, rect: &Rect) {
+        let black = u32::from(Color::black());
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if !rect.contains(x, y) {
+                    let index = self.index(x, y);
+                    self.buffer[index] = black;
+                }
+            }
+        }
+    }
+
+    pub fn write_png(&self, path: &Path) -> Result<()> {
+        let file = File::create(path)?;
+        let mut encoder =
+            png::Encoder::new(BufWriter::new(file), self.width as u32, self.height as u32);
+        encoder.set_color(png::ColorType::RGB);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let data = self
+            .buffer
+            .iter()
+            .flat_map(|pixel| {
+                vec![
+                    ((pixel >> 16) & 0xff) as u8,
+                    ((pixel >> 8) & 0xff) as u8,
+                    (pixel & 0xff) as u8,
+                ]
+            })
+            .collect::<Vec<u8>>();
+        encoder.write_header()?.write_image_data(&data)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_converged() {
+        let mut ifb = IncrementalFrameBuffer::new(2, 1).unwrap();
+        for i in 0..8 {
+            ifb.set(0, 0, &Vec3::cartesian(0.5, 0.5, 0.5));
+            let noisy = if i % 2 == 0 { 0.1 } else { 0.9 };
+            ifb.set(1, 0, &Vec3::cartesian(noisy, noisy, noisy));
+        }
+
+        assert_eq!(8, ifb.count(0, 0));
+        assert!(ifb.converged(0, 0, 0.01, 4));
+        assert!(!ifb.converged(0, 0, 0.01, 16));
+        assert!(!ifb.converged(1, 0, 0.01, 4));
+    }
+
+    #[test]
+    fn test_splat() {
+        let filter = Filter::new(FilterKind::Tent, 1.0).unwrap();
+        let mut ifb = IncrementalFrameBuffer::with_filter(3, 1, filter).unwrap();
+        ifb.splat(1.75, 0.5, &Vec3::cartesian(1.0, 1.0, 1.0));
+
+        // Only the originating pixel counts the sample...
+        assert_eq!(0, ifb.count(0, 0));
+        assert_eq!(1, ifb.count(1, 0));
+        assert_eq!(0, ifb.count(2, 0));
+
+        // ...but it is spread to the neighbor it is closest to.
+        assert_eq!(0.0, ifb.weights[ifb.pixel_index(0, 0)]);
+        assert!((ifb.weights[ifb.pixel_index(1, 0)] - 0.75).abs() < 1.0e-6);
+        assert!((ifb.weights[ifb.pixel_index(2, 0)] - 0.25).abs() < 1.0e-6);
+    }
+
+    #[test]
+    fn test_crop() {
+        let mut fb = FrameBuffer::new(4, 3).unwrap();
+        for y in 0..3 {
+            for x in 0..4 {
+                fb.set(x, y, Color::white());
+            }
+        }
+        fb.set(1, 2, Color::new(1.0, 0.0, 0.0).unwrap());
+
+        let rect = Rect {
+            x0: 1,
+            y0: 1,
+            x1: 3,
+            y1: 3,
+        };
+        let cropped = fb.crop(&rect).unwrap();
+        assert_eq!((2, 2), (cropped.width(), cropped.height()));
+        assert_eq!(
+            u32::from(Color::new(1.0, 0.0, 0.0).unwrap()),
+            cropped.buffer()[0]
+        );
+
+        fb.clear_outside(&rect);
+        assert_eq!(u32::from(Color::black()), fb.buffer()[fb.index(0, 2)]);
+        assert_eq!(u32::from(Color::black()), fb.buffer()[fb.index(3, 1)]);
+        assert_eq!(u32::from(Color::white()), fb.buffer()[fb.index(2, 1)]);
+    }
+
+    #[test]
+    fn test_write_exr() {
+        let names = vec!["albedo.R".to_string(), "Z".to_string()];
+        let filter = Filter::with_default_radius(FilterKind::Box);
+        let mut ifb = IncrementalFrameBuffer::with_channels(2, 1, filter, names).unwrap();
+        ifb.splat_channels(0.5, 0.5, &Vec3::cartesian(2.0, 0.5, 0.25), &[0.75, 3.0]);
+        ifb.splat_channels(0.5, 0.5, &Vec3::cartesian(2.0, 0.5, 0.25), &[0.25, 5.0]);
+
+        let path = std::env::temp_dir().join(format!("myray_test_{}.exr", std::process::id()));
+        ifb.write_exr(&path, true).unwrap();
+        let image = exr::prelude::read_first_flat_layer_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let channel = |name: &str| {
+            let channel = image
+                .layer_data
+                .channel_data
+                .list
+                .iter()
+                .find(|channel| channel.name.to_string() == name)
+                .unwrap();
+            channel.sample_data.values_as_f32().collect::<Vec<_>>()
+        };
+        assert_eq!(vec![2.0, 0.0], channel("R"));
+        assert_eq!(vec![0.5, 0.0], channel("albedo.R"));
+        assert_eq!(vec![4.0, 0.0], channel("Z"));
     }
 }
@@ -0,0 +1,125 @@
+use std::f32;
+use std::str::FromStr;
+
+use crate::errors::*;
+
+#[derive(Debug, Copy, Clone)]
+pub enum FilterKind {
+    Box,
+    Tent,
+    Gaussian,
+    Mitchell,
+    Lanczos,
+}
+
+impl FilterKind {
+    pub fn default_radius(self) -> f32 {
+        match self {
+            FilterKind::Box => 0.5,
+            FilterKind::Tent => 1.0,
+            FilterKind::Gaussian => 1.5,
+            FilterKind::Mitchell => 2.0,
+            FilterKind::Lanczos => 3.0,
+        }
+    }
+}
+
+impl FromStr for FilterKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<FilterKind> {
+        match s.to_lowercase().as_str() {
+            "box" => Ok(FilterKind::Box),
+            "tent" | "triangle" => Ok(FilterKind::Tent),
+            "gaussian" => Ok(FilterKind::Gaussian),
+            "mitchell" => Ok(FilterKind::Mitchell),
+            "lanczos" => Ok(FilterKind::Lanczos),
+            _ => Err(ErrorKind::ParseError(
+                s.to_string(),
+                "Must be 'box', 'tent', 'gaussian', 'mitchell', or 'lanczos'.".to_string(),
+            )
+            .into()),
+        }
+    }
+}
+
+// A separable pixel reconstruction filter. Samples contribute to every pixel whose center is
+// within `radius` pixels (on each axis) of the sample position.
+#[derive(Debug, Copy, Clone)]
+pub struct Filter {
+    kind: FilterKind,
+    radius: f32,
+}
+
+impl Filter {
+    // radius must be > 0.0
+    pub fn new(kind: FilterKind, radius: f32) -> Result<Filter> {
+        if radius <= 0.0 {
+            Err(ErrorKind::InvalidParam(radius, "filter radius must be > 0.0".into()).into())
+        } else {
+            Ok(Filter { kind, radius })
+        }
+    }
+
+    pub fn with_default_radius(kind: FilterKind) -> Filter {
+        Filter {
+            kind,
+            radius: kind.default_radius(),
+        }
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    pub fn weight(&self, dx: f32, dy: f32) -> f32 {
+        self.weight_1d(dx) * self.weight_1d(dy)
+    }
+
+    fn weight_1d(&self, d: f32) -> f32 {
+        let d = d.abs();
+        if d > self.radius {
+            return 0.0;
+        }
+
+        match self.kind {
+            FilterKind::Box => 1.0,
+            FilterKind::Tent => self.radius - d,
+            FilterKind::Gaussian => {
+                // alpha controls the falloff; subtracting the value at the radius takes the
+                // filter smoothly to zero at its edge.
+                let alpha = 2.0;
+                f32::exp(-alpha * d * d) - f32::exp(-alpha * self.radius * self.radius)
+            }
+            FilterKind::Mitchell => mitchell(2.0 * d / self.radius),
+            FilterKind::Lanczos => sinc(d) * sinc(d / self.radius),
+        }
+    }
+}
+
+// Mitchell-Netravali with B = C = 1/3, defined over [0, 2].
+fn mitchell(x: f32) -> f32 {
+    let b = 1.0 / 3.0;
+    let c = 1.0 / 3.0;
+    let x2 = x * x;
+    let x3 = x2 * x;
+    if x > 1.0 {
+        ((-b - 6.0 * c) * x3
+            + (6.0 * b + 30.0 * c) * x2
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c))
+            / 6.0
+    } else {
+        ((12.0 - 9.0 * b - 6.0 * c) * x3 + (-18.0 + 12.0 * b + 6.0 * c) * x2 + (6.0 - 2.0 * b))
+            / 6.0
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x.abs() < 1.0e-5 {
+        1.0
+    } else {
+        let px = f32::consts::PI * x;
+        f32::sin(px) / px
+    }
+}
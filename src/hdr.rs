@@ -0,0 +1,159 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use crate::errors::*;
+use crate::vec3::Vec3;
+
+// The largest image accepted, in pixels (16384 x 8192), so that a bad header cannot ask for an
+// enormous allocation.
+const MAX_PIXELS: usize = 1 << 27;
+
+// A floating point image read from a Radiance RGBE (.hdr) file.
+// Pixels are stored row-major, starting at the top left.
+pub struct HdrImage {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3>,
+}
+
+impl HdrImage {
+    pub fn open(path: &Path) -> Result<HdrImage> {
+        let file = File::open(path)?;
+        HdrImage::read(BufReader::new(file))
+    }
+
+    pub fn read<R: BufRead>(mut reader: R) -> Result<HdrImage> {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        if !line.starts_with("#?") {
+            return Err(parse_error(&line, "Not a Radiance HDR file."));
+        }
+
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                return Err(parse_error(&line, "Missing HDR resolution line."));
+            }
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                break;
+            }
+            if trimmed.starts_with("FORMAT=") && trimmed != "FORMAT=32-bit_rle_rgbe" {
+                return Err(parse_error(trimmed, "Only RGBE HDR files are supported."));
+            }
+        }
+
+        line.clear();
+        reader.read_line(&mut line)?;
+        let pieces = line.split_whitespace().collect::<Vec<_>>();
+        if pieces.len() != 4 || pieces[0] != "-Y" || pieces[2] != "+X" {
+            return Err(parse_error(
+                &line,
+                "Only the '-Y height +X width' orientation is supported.",
+            ));
+        }
+        let height = pieces[1].parse::<usize>()?;
+        let width = pieces[3].parse::<usize>()?;
+        match width.checked_mul(height) {
+            Some(count) if count > 0 && count <= MAX_PIXELS => {}
+            _ => return Err(parse_error(&line, "HDR image size is empty or too large.")),
+        }
+
+        let mut pixels = Vec::with_capacity(width * height);
+        let mut scanline = vec![[0u8; 4]; width];
+        for _ in 0..height {
+            read_scanline(&mut reader, &mut scanline)?;
+            pixels.extend(scanline.iter().map(rgbe_to_vec));
+        }
+
+        Ok(HdrImage {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> &Vec3 {
+        &self.pixels[y * self.width + x]
+    }
+
+    pub fn pixels(&self) -> &[Vec3] {
+        &self.pixels
+    }
+}
+
+fn parse_error(val: &str, msg: &str) -> Error {
+    ErrorKind::ParseError(val.trim().to_string(), msg.to_string()).into()
+}
+
+fn read_scanline<R: Read>(reader: &mut R, scanline: &mut [[u8; 4]]) -> Result<()> {
+    let width = scanline.len();
+    let mut first = [0u8; 4];
+    reader.read_exact(&mut first)?;
+
+    let is_rle = (8..0x8000).contains(&width)
+        && first[0] == 2
+        && first[1] == 2
+        && (usize::from(first[2]) << 8 | usize::from(first[3])) == width;
+    if !is_rle {
+        // Uncompressed scanline.
+        scanline[0] = first;
+        for pixel in scanline.iter_mut().skip(1) {
+            reader.read_exact(pixel)?;
+        }
+        return Ok(());
+    }
+
+    // Run-length encoded scanline: each of the four channels is stored separately.
+    let mut byte = [0u8; 1];
+    for channel in 0..4 {
+        let mut x = 0;
+        while x < width {
+            reader.read_exact(&mut byte)?;
+            let count = usize::from(byte[0]);
+            if count > 128 {
+                let run = count - 128;
+                if x + run > width {
+                    return Err(parse_error(&run.to_string(), "HDR run exceeds scanline."));
+                }
+                reader.read_exact(&mut byte)?;
+                for pixel in &mut scanline[x..x + run] {
+                    pixel[channel] = byte[0];
+                }
+                x += run;
+            } else {
+                if count == 0 || x + count > width {
+                    return Err(parse_error(&count.to_string(), "Bad HDR scanline count."));
+                }
+                for pixel in &mut scanline[x..x + count] {
+                    reader.read_exact(&mut byte)?;
+                    pixel[channel] = byte[0];
+                }
+                x += count;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn rgbe_to_vec(rgbe: &[u8; 4]) -> Vec3 {
+    if rgbe[3] == 0 {
+        Vec3::origin()
+    } else {
+        let f = f32::powi(2.0, i32::from(rgbe[3]) - (128 + 8));
+        Vec3::cartesian(
+            f32::from(rgbe[0]) * f,
+            f32::from(rgbe[1]) * f,
+            f32::from(rgbe[2]) * f,
+        )
+    }
+}
@@ -7,6 +7,12 @@ pub struct HitRecord<'a> {
     pub point: Vec3,
     pub normal: Vec3,
     pub material: &'a dyn Material,
+
+    // Surface parameterization of the hit point, each in [0, 1].
+    pub uv: (f32, f32),
+
+    // Index of the object hit in the list of objects that was tested.
+    pub object: usize,
 }
 
 pub trait HitTest {
@@ -20,9 +26,10 @@ where
     fn hit_test(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord> {
         let mut hit_record = None;
         let mut closest_so_far = t_max;
-        for test in self {
-            if let Some(hit) = test.hit_test(ray, t_min, closest_so_far) {
+        for (index, test) in self.iter().enumerate() {
+            if let Some(mut hit) = test.hit_test(ray, t_min, closest_so_far) {
                 closest_so_far = hit.t;
+                hit.object = index;
                 hit_record = Some(hit);
             }
         }
@@ -0,0 +1,702 @@
+use std::f32;
+use std::str::FromStr;
+
+use crate::config::Config;
+use crate::environment::Environment;
+use crate::errors::*;
+use crate::hittest::{HitRecord, HitTest};
+use crate::light::Light;
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::stats::{count_max_depth_termination, count_path, count_ray, RayKind};
+use crate::unit_random::unit_random;
+use crate::util::random_in_unit_sphere;
+use crate::vec3::{dot, Vec3};
+
+// The depth written for camera rays that hit nothing. Filtering it together with the depths of
+// nearby surfaces must stay finite, and it must fit in the half floats written to --aov_output.
+pub(crate) const MISS_DISTANCE: f32 = 1.0e4;
+
+// The algorithm used to find the light arriving at the camera.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Integrator {
+    Path,
+    Bidirectional,
+    PhotonMapping,
+    Metropolis,
+    AmbientOcclusion,
+    Whitted,
+}
+
+impl FromStr for Integrator {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Integrator> {
+        match s.to_lowercase().as_str() {
+            "path" => Ok(Integrator::Path),
+            "bdpt" | "bidirectional" => Ok(Integrator::Bidirectional),
+            "ppm" | "photon" => Ok(Integrator::PhotonMapping),
+            "mlt" | "metropolis" => Ok(Integrator::Metropolis),
+            "ao" => Ok(Integrator::AmbientOcclusion),
+            "whitted" => Ok(Integrator::Whitted),
+            _ => Err(ErrorKind::ParseError(
+                s.to_string(),
+                "Must be 'path', 'bdpt', 'ppm', 'mlt', 'ao', or 'whitted'.".to_string(),
+            )
+            .into()),
+        }
+    }
+}
+
+// The light arriving at the camera along ray, found by following a random path through the
+// scene.
+pub fn radiance(
+    ray: &Ray,
+    config: &Config,
+    scene: &Scene,
+    environment: &dyn Environment,
+) -> Result<Vec3> {
+    count_ray(RayKind::Primary);
+    match scene.hit_test(ray, 0.001, f32::MAX) {
+        Some(hit_record) => trace(
+            ray,
+            hit_record,
+            config,
+            scene,
+            environment,
+            PathState::camera(),
+        ),
+        None => escape(ray, environment, 0, false),
+    }
+}
+
+// Where a path has got to on its way through the scene.
+#[derive(Debug, Copy, Clone)]
+struct PathState {
+    // The number of surfaces hit so far.
+    depth: u8,
+
+    // The environment was already sampled directly from the previous hit, so rays escaping the
+    // scene must not count it a second time.
+    skip_environment: bool,
+
+    // The path has hit a diffuse surface, from which point its bounces may be roughened.
+    after_diffuse: bool,
+
+    // The product of the attenuations along the path so far.
+    throughput: Vec3,
+}
+
+impl PathState {
+    fn camera() -> PathState {
+        PathState {
+            depth: 0,
+            skip_environment: false,
+            after_diffuse: false,
+            throughput: Vec3::cartesian(1.0, 1.0, 1.0),
+        }
+    }
+
+    // The state after scattering off a surface.
+    fn bounce(&self, attenuation: &Vec3, skip_environment: bool, diffuse: bool) -> PathState {
+        PathState {
+            depth: self.depth + 1,
+            skip_environment,
+            after_diffuse: self.after_diffuse || diffuse,
+            throughput: self.throughput * *attenuation,
+        }
+    }
+}
+
+// Follows a path on from hit_record, where ray hit a surface, until it leaves the scene, is
+// absorbed, or is ended by --max_depth or Russian roulette. Returns the light leaving that
+// surface back along ray.
+//
+// The light found at each surface is weighted by the attenuation of every bounce before it,
+// so it can be added to the total as soon as it is found.
+fn trace(
+    ray: &Ray,
+    hit_record: HitRecord,
+    config: &Config,
+    scene: &Scene,
+    environment: &dyn Environment,
+    state: PathState,
+) -> Result<Vec3> {
+    let start_depth = state.depth;
+    let mut state = state;
+    let mut ray = Ray::new(*ray.origin(), *ray.direction());
+    let mut hit_record = hit_record;
+
+    // The throughput, scaled up to make up for the paths ended by Russian roulette.
+    let mut weight = Vec3::cartesian(1.0, 1.0, 1.0);
+
+    // The light found at the first surface, and at all the surfaces after it.
+    let mut first = Vec3::origin();
+    let mut rest = Vec3::origin();
+
+    loop {
+        let depth = state.depth;
+        if depth >= config.max_depth {
+            count_max_depth_termination();
+            count_path(usize::from(depth) + 1);
+            break;
+        }
+
+        let mut direct = Vec3::origin();
+        for light in &scene.lights {
+            direct = direct + light_contribution(scene, &hit_record, light.as_ref())?;
+        }
+        let sampled_environment =
+            environment_contribution(config, scene, environment, &hit_record)?;
+        let sampled = sampled_environment.is_some();
+        direct = direct + sampled_environment.unwrap_or_else(Vec3::origin);
+        if depth == start_depth {
+            first = direct;
+        } else {
+            rest = rest + weight * direct;
+        }
+
+        let (scattered, attenuation) = match hit_record.material.scatter(&ray, &hit_record)? {
+            Some(scattered) => scattered,
+            None => {
+                count_path(usize::from(depth) + 1);
+                break;
+            }
+        };
+        let diffuse = is_diffuse(&hit_record);
+        let scattered = if state.after_diffuse && !diffuse {
+            roughen(config, scattered, &hit_record)?
+        } else {
+            scattered
+        };
+        state = state.bounce(&attenuation, sampled, diffuse);
+        let survival = match roulette(config, &state) {
+            Some(survival) => survival,
+            None => {
+                count_path(usize::from(depth) + 1);
+                break;
+            }
+        };
+        weight = weight * ((1.0 / survival) * attenuation);
+
+        count_ray(RayKind::Secondary);
+        match scene.hit_test(&scattered, 0.001, f32::MAX) {
+            Some(next_hit) => {
+                ray = scattered;
+                hit_record = next_hit;
+            }
+            None => {
+                let escaped = escape(&scattered, environment, state.depth, state.skip_environment)?;
+                rest = rest + weight * escaped;
+                break;
+            }
+        }
+    }
+
+    if start_depth == 0 {
+        rest = clamp_indirect(config, &rest);
+    }
+    Ok(first + rest)
+}
+
+// Russian roulette: once a path has hit --roulette_depth surfaces, it continues with a
+// probability equal to the largest channel of its throughput, clamped to between 0.05 and 1,
+// and the light it finds is divided by that probability. Dim paths end early, while paths
+// through glass and mirrors carry on, and the expected value of every sample is unchanged. The
+// 0.05 floor stops the few very dim paths that survive from being scaled up into fireflies.
+// Returns None if the path ends, and the probability that it continued otherwise.
+fn roulette(config: &Config, next: &PathState) -> Option<f32> {
+    match config.roulette_depth {
+        Some(min_depth) if next.depth >= min_depth => {
+            let throughput = next.throughput;
+            let max = f32::max(throughput.x(), f32::max(throughput.y(), throughput.z()));
+            let survival = max.clamp(0.05, 1.0);
+            if unit_random() < survival {
+                Some(survival)
+            } else {
+                None
+            }
+        }
+        _ => Some(1.0),
+    }
+}
+
+// Light from the environment along a ray that left the scene.
+fn escape(
+    ray: &Ray,
+    environment: &dyn Environment,
+    depth: u8,
+    skip_environment: bool,
+) -> Result<Vec3> {
+    count_path(usize::from(depth));
+    if skip_environment {
+        Ok(Vec3::origin())
+    } else {
+        let unit_direction = ray.direction().unit_vector()?;
+        Ok(environment.radiance(&unit_direction))
+    }
+}
+
+// Only diffuse surfaces have a BSDF for light arriving from a given direction.
+pub(crate) fn is_diffuse(hit_record: &HitRecord) -> bool {
+    hit_record
+        .material
+        .bsdf(hit_record, &hit_record.normal)
+        .is_some()
+}
+
+// Scales down the light a sample gathers by scattering off the first surface it hits, so that
+// no channel exceeds --clamp_indirect.
+fn clamp_indirect(config: &Config, radiance: &Vec3) -> Vec3 {
+    let max = f32::max(radiance.x(), f32::max(radiance.y(), radiance.z()));
+    match config.clamp_indirect {
+        Some(limit) if max > limit => (limit / max) * *radiance,
+        _ => *radiance,
+    }
+}
+
+// Blurs the direction of a mirror or glass bounce by --roughen, keeping it on the same side of
+// the surface.
+fn roughen(config: &Config, scattered: Ray, hit_record: &HitRecord) -> Result<Ray> {
+    let roughness = match config.roughen {
+        Some(roughness) => roughness,
+        None => return Ok(scattered),
+    };
+    let direction = scattered.direction().unit_vector()?;
+    let blurred = direction + roughness * random_in_unit_sphere();
+    if dot(&direction, &hit_record.normal) * dot(&blurred, &hit_record.normal) <= 0.0 {
+        return Ok(scattered);
+    }
+    Ok(Ray::new(*scattered.origin(), blurred))
+}
+
+// Direct lighting at a hit point from one of the scene's point, spot, and directional lights.
+pub(crate) fn light_contribution(
+    scene: &Scene,
+    hit_record: &HitRecord,
+    light: &dyn Light,
+) -> Result<Vec3> {
+    if let Some(sample) = light.sample(&hit_record.point)? {
+        if let Some(bsdf) = hit_record.material.bsdf(hit_record, &sample.direction) {
+            count_ray(RayKind::Shadow);
+            let shadow_ray = Ray::new(hit_record.point, sample.direction);
+            if scene
+                .hit_test(&shadow_ray, 0.001, sample.distance)
+                .is_none()
+            {
+                return Ok(bsdf * sample.radiance);
+            }
+        }
+    }
+    Ok(Vec3::origin())
+}
+
+// Direct lighting at a hit point from a sample of the environment, or None if the environment
+// was not sampled (because --light_sampling is off, or the surface is not diffuse).
+fn environment_contribution(
+    config: &Config,
+    scene: &Scene,
+    environment: &dyn Environment,
+    hit_record: &HitRecord,
+) -> Result<Option<Vec3>> {
+    if !config.light_sampling {
+        return Ok(None);
+    }
+    if let Some(sample) = environment.sample() {
+        if let Some(bsdf) = hit_record.material.bsdf(hit_record, &sample.direction) {
+            count_ray(RayKind::Shadow);
+            let shadow_ray = Ray::new(hit_record.point, sample.direction);
+            if scene.hit_test(&shadow_ray, 0.001, f32::MAX).is_none() {
+                return Ok(Some(bsdf * sample.radiance / sample.pdf));
+            }
+            return Ok(Some(Vec3::origin()));
+        }
+    }
+    Ok(None)
+}
+
+// Names of the render passes written to --aov_output, in the order sample_aovs returns them.
+pub fn aov_channel_names(scene: &Scene) -> Vec<String> {
+    let mut layers = vec![
+        "emission",
+        "diffuse_direct",
+        "diffuse_indirect",
+        "specular",
+        "albedo",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect::<Vec<_>>();
+    layers.extend((0..scene.lights.len()).map(|index| format!("light{}", index)));
+
+    let mut names = vec![];
+    for layer in layers {
+        names.extend(["R", "G", "B"].iter().map(|c| format!("{}.{}", layer, c)));
+    }
+    names.extend(
+        ["normal.X", "normal.Y", "normal.Z", "Z"]
+            .iter()
+            .map(|c| c.to_string()),
+    );
+    names
+}
+
+pub fn aov_channel_count(scene: &Scene) -> usize {
+    3 * (5 + scene.lights.len()) + 4
+}
+
+// Traces a camera ray like radiance(), also splitting the light it carries into render passes.
+//
+// Emission is the environment seen directly. Light reaching the first surface straight from a
+// light or the environment is direct diffuse lighting, and the rest of the light leaving a
+// diffuse surface is indirect. Everything seen through a mirror or glass surface is specular.
+// The per-light passes are the direct lighting from each light at the first surface.
+pub fn sample_aovs(
+    ray: &Ray,
+    config: &Config,
+    scene: &Scene,
+    environment: &dyn Environment,
+) -> Result<(Vec3, Vec<f32>)> {
+    let black = Vec3::origin();
+    let mut emission = black;
+    let mut diffuse_direct = black;
+    let mut diffuse_indirect = black;
+    let mut specular = black;
+    let mut albedo = black;
+    let mut normal = black;
+    let mut distance = MISS_DISTANCE;
+    let mut lights = vec![black; scene.lights.len()];
+
+    count_ray(RayKind::Primary);
+    match scene.hit_test(ray, 0.001, f32::MAX) {
+        None => emission = escape(ray, environment, 0, false)?,
+        Some(_) if config.max_depth == 0 => {
+            count_max_depth_termination();
+            count_path(1);
+        }
+        Some(hit_record) => {
+            albedo = hit_record.material.albedo();
+            normal = hit_record.normal;
+            distance = f32::min(hit_record.t * ray.direction().length(), MISS_DISTANCE);
+
+            for (light, contribution) in scene.lights.iter().zip(lights.iter_mut()) {
+                *contribution = light_contribution(scene, &hit_record, light.as_ref())?;
+            }
+            let sampled_environment =
+                environment_contribution(config, scene, environment, &hit_record)?;
+            let sampled = sampled_environment.is_some();
+
+            let diffuse = is_diffuse(&hit_record);
+            let mut direct = lights
+                .iter()
+                .fold(sampled_environment.unwrap_or(black), |sum, light| {
+                    sum + *light
+                });
+            let mut indirect = black;
+
+            if let Some((scattered, attenuation)) = hit_record.material.scatter(ray, &hit_record)? {
+                let next = PathState::camera().bounce(&attenuation, sampled, diffuse);
+                if let Some(survival) = roulette(config, &next) {
+                    let attenuation = (1.0 / survival) * attenuation;
+                    count_ray(RayKind::Secondary);
+                    match scene.hit_test(&scattered, 0.001, f32::MAX) {
+                        Some(next_hit) => {
+                            indirect = clamp_indirect(
+                                config,
+                                &(attenuation
+                                    * trace(
+                                        &scattered,
+                                        next_hit,
+                                        config,
+                                        scene,
+                                        environment,
+                                        next,
+                                    )?),
+                            );
+                        }
+                        None => {
+                            direct = direct
+                                + clamp_indirect(
+                                    config,
+                                    &(attenuation * escape(&scattered, environment, 1, sampled)?),
+                                );
+                        }
+                    }
+                } else {
+                    count_path(1);
+                }
+            } else {
+                count_path(1);
+            }
+
+            if diffuse {
+                diffuse_direct = direct;
+                diffuse_indirect = indirect;
+            } else {
+                specular = direct + indirect;
+            }
+        }
+    }
+
+    let beauty = emission + diffuse_direct + diffuse_indirect + specular;
+    let mut values = vec![];
+    for layer in [emission, diffuse_direct, diffuse_indirect, specular, albedo]
+        .iter()
+        .chain(lights.iter())
+    {
+        values.extend_from_slice(&[layer.x(), layer.y(), layer.z()]);
+    }
+    values.extend_from_slice(&[normal.x(), normal.y(), normal.z(), distance]);
+    Ok((beauty, values))
+}
+
+#[cfg(test)]
+mod test {
+    use structopt::StructOpt;
+
+    use super::*;
+    use crate::color::Color;
+    use crate::environment::Gradient;
+    use crate::fb::IncrementalFrameBuffer;
+    use crate::filter::{Filter, FilterKind};
+    use crate::light::PointLight;
+    use crate::material::{Lambertian, Metal};
+    use crate::sphere::Sphere;
+    use crate::unit_random::seed_unit_random;
+    use crate::world::{load_world, Worlds};
+
+    struct Black;
+
+    impl Environment for Black {
+        fn radiance(&self, _direction: &Vec3) -> Vec3 {
+            Vec3::origin()
+        }
+    }
+
+    fn mean_radiance(args: &[&str], scene: &Scene, ray: &Ray) -> Vec3 {
+        let config = Config::from_iter(args);
+        let environment = Gradient::new(200.0).unwrap();
+        let samples = 20000;
+        let mut sum = Vec3::origin();
+        for sample in 0..samples {
+            seed_unit_random(sample);
+            sum = sum + radiance(ray, &config, scene, &environment).unwrap();
+        }
+        sum / samples as f32
+    }
+
+    // The recursive path tracer that trace() replaced: one call per bounce, adding up the light
+    // on the way back out.
+    fn recursive_color(
+        ray: &Ray,
+        config: &Config,
+        scene: &Scene,
+        environment: &dyn Environment,
+        state: PathState,
+    ) -> Result<Vec3> {
+        let hit_record = match scene.hit_test(ray, 0.001, f32::MAX) {
+            Some(hit_record) => hit_record,
+            None => return escape(ray, environment, state.depth, state.skip_environment),
+        };
+        if state.depth >= config.max_depth {
+            return Ok(Vec3::origin());
+        }
+
+        let mut direct = Vec3::origin();
+        for light in &scene.lights {
+            direct = direct + light_contribution(scene, &hit_record, light.as_ref())?;
+        }
+        let sampled_environment =
+            environment_contribution(config, scene, environment, &hit_record)?;
+        let sampled = sampled_environment.is_some();
+        direct = direct + sampled_environment.unwrap_or_else(Vec3::origin);
+
+        if let Some((scattered, attenuation)) = hit_record.material.scatter(ray, &hit_record)? {
+            let diffuse = is_diffuse(&hit_record);
+            let scattered = if state.after_diffuse && !diffuse {
+                roughen(config, scattered, &hit_record)?
+            } else {
+                scattered
+            };
+            let next = state.bounce(&attenuation, sampled, diffuse);
+            let survival = match roulette(config, &next) {
+                Some(survival) => survival,
+                None => return Ok(direct),
+            };
+            let indirect = (1.0 / survival)
+                * attenuation
+                * recursive_color(&scattered, config, scene, environment, next)?;
+            if state.depth == 0 {
+                return Ok(direct + clamp_indirect(config, &indirect));
+            }
+            return Ok(direct + indirect);
+        }
+        Ok(direct)
+    }
+
+    #[test]
+    fn test_matches_recursion() {
+        let mut scene = Scene::from_world(load_world(Worlds::ThreeBalls).unwrap());
+        scene.lights.push(Box::new(PointLight::new(
+            Vec3::cartesian(0.0, 2.0, 0.0),
+            Vec3::cartesian(3.0, 3.0, 3.0),
+        )));
+        let environment = Gradient::new(200.0).unwrap();
+
+        // Every option that changes how a path continues, so that each draws its random numbers
+        // in the same order in both versions.
+        let options: &[&[&str]] = &[
+            &["myray"],
+            &["myray", "--light_sampling", "--max_depth", "4"],
+            &["myray", "--roulette_depth", "2", "--clamp_indirect", "0.5"],
+            &["myray", "--roughen", "0.3"],
+        ];
+        for args in options {
+            let config = Config::from_iter(*args);
+            for i in 0..25 {
+                let (x, y) = ((i % 5) as f32 * 0.5 - 1.0, (i / 5) as f32 * 0.25 - 0.5);
+                let ray = Ray::new(Vec3::origin(), Vec3::cartesian(x, y, -1.0));
+                for sample in 0..20 {
+                    seed_unit_random(sample);
+                    let looped = radiance(&ray, &config, &scene, &environment).unwrap();
+                    seed_unit_random(sample);
+                    let recursed =
+                        recursive_color(&ray, &config, &scene, &environment, PathState::camera())
+                            .unwrap();
+                    assert!(
+                        (looped - recursed).length() <= 1.0e-4 * (1.0 + recursed.length()),
+                        "{:?} {:?}: {:?} != {:?}",
+                        args,
+                        ray.direction(),
+                        looped,
+                        recursed
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_roulette_unbiased() {
+        // A bright ball sitting on a bright floor, so that paths bounce between them many times.
+        let white = || Lambertian::new(Color::new(0.9, 0.9, 0.9).unwrap());
+        let scene = Scene::from_world(vec![
+            Sphere::new(&Vec3::cartesian(0.0, -100.5, -1.0), 100.0, white()).unwrap(),
+            Sphere::new(&Vec3::cartesian(0.0, 0.0, -1.0), 0.5, white()).unwrap(),
+        ]);
+        let ray = Ray::new(Vec3::origin(), Vec3::cartesian(0.1, -0.4, -1.0));
+
+        let fixed = mean_radiance(&["myray"], &scene, &ray);
+        let roulette = mean_radiance(&["myray", "--roulette_depth", "1"], &scene, &ray);
+        assert!(fixed.x() > 0.1);
+        assert!((fixed - roulette).length() < 0.02 * fixed.length());
+    }
+
+    #[test]
+    fn test_clamp_indirect() {
+        // A bright ball on a bright floor, lit only by a point light, so the direct light at
+        // each hit is exact and the indirect light is everything that bounced on.
+        let white = || Lambertian::new(Color::new(0.9, 0.9, 0.9).unwrap());
+        let mut scene = Scene::from_world(vec![
+            Sphere::new(&Vec3::cartesian(0.0, -100.5, -1.0), 100.0, white()).unwrap(),
+            Sphere::new(&Vec3::cartesian(0.0, 0.0, -1.0), 0.5, white()).unwrap(),
+        ]);
+        scene.lights.push(Box::new(PointLight::new(
+            Vec3::cartesian(0.0, 1.0, -0.2),
+            Vec3::cartesian(8.0, 4.0, 2.0),
+        )));
+        let free = Config::from_iter(&["myray"]);
+        let clamped = Config::from_iter(&["myray", "--clamp_indirect", "0.2"]);
+
+        // The direct and indirect diffuse passes.
+        let ray = Ray::new(Vec3::origin(), Vec3::cartesian(0.8, -0.5, -1.0));
+        let passes = |config: &Config| {
+            let (_, values) = sample_aovs(&ray, config, &scene, &Black).unwrap();
+            let pass = |i: usize| Vec3::cartesian(values[i], values[i + 1], values[i + 2]);
+            (pass(3), pass(6))
+        };
+        let max = |v: Vec3| f32::max(v.x(), f32::max(v.y(), v.z()));
+        let mut clamped_samples = 0;
+        for sample in 0..200 {
+            seed_unit_random(sample);
+            let (direct, indirect) = passes(&free);
+            seed_unit_random(sample);
+            let (clamped_direct, clamped_indirect) = passes(&clamped);
+
+            assert!(direct.x() > 0.0);
+            assert_eq!(direct, clamped_direct);
+            assert!(max(clamped_indirect) <= 0.2 + 1.0e-6);
+            if indirect == clamped_indirect {
+                assert!(max(indirect) <= 0.2);
+            } else {
+                clamped_samples += 1;
+            }
+        }
+        assert!(clamped_samples > 0);
+    }
+
+    #[test]
+    fn test_roughen() {
+        let mirror = |x| {
+            let metal = Metal::new(Color::new(0.9, 0.9, 0.9).unwrap());
+            Sphere::new(&Vec3::cartesian(x, 0.0, -1.5), 0.5, metal).unwrap()
+        };
+        let floor = Sphere::new(
+            &Vec3::cartesian(0.0, -100.5, -1.5),
+            100.0,
+            Lambertian::new(Color::new(0.5, 0.5, 0.5).unwrap()),
+        )
+        .unwrap();
+        let environment = Gradient::new(200.0).unwrap();
+        let sharp = Config::from_iter(&["myray"]);
+        let rough = Config::from_iter(&["myray", "--roughen", "0.5"]);
+        let differ = |scene: &Scene, ray: &Ray| {
+            (0..50).any(|sample| {
+                seed_unit_random(sample);
+                let a = radiance(ray, &sharp, scene, &environment).unwrap();
+                seed_unit_random(sample);
+                a != radiance(ray, &rough, scene, &environment).unwrap()
+            })
+        };
+
+        // Mirrors reflecting each other and the sky are untouched: no path hits a diffuse
+        // surface.
+        let mirrors = Scene::from_world(vec![mirror(-0.5), mirror(0.5)]);
+        let between = Ray::new(Vec3::origin(), Vec3::cartesian(0.1, 0.05, -1.0));
+        assert!(mirrors.hit_test(&between, 0.001, f32::MAX).is_some());
+        assert!(!differ(&mirrors, &between));
+
+        // Once a path has bounced off the floor, the mirrors it sees are blurred.
+        let mut world = vec![mirror(-0.5), mirror(0.5)];
+        world.push(floor);
+        let on_floor = Ray::new(Vec3::origin(), Vec3::cartesian(0.0, -0.5, -0.8));
+        assert!(differ(&Scene::from_world(world), &on_floor));
+    }
+
+    #[test]
+    fn test_miss_depth() {
+        let scene = Scene::from_world(vec![Sphere::new(
+            &Vec3::cartesian(0.0, 0.0, -1.0),
+            0.5,
+            Lambertian::new(Color::new(0.5, 0.5, 0.5).unwrap()),
+        )
+        .unwrap()]);
+        let config = Config::from_iter(&["myray"]);
+        let environment = Gradient::new(200.0).unwrap();
+
+        // Samples that hit the ball and samples that miss it, splatted next to each other with
+        // a filter whose negative lobes subtract some of them, still give finite depths.
+        let filter = Filter::with_default_radius(FilterKind::Mitchell);
+        let mut ifb =
+            IncrementalFrameBuffer::with_channels(4, 1, filter, aov_channel_names(&scene)).unwrap();
+        for (px, dx) in &[(0.5, 0.0), (1.5, 0.0), (2.5, 2.0), (3.5, 2.0)] {
+            let ray = Ray::new(Vec3::origin(), Vec3::cartesian(*dx, 0.0, -1.0));
+            let (beauty, values) = sample_aovs(&ray, &config, &scene, &environment).unwrap();
+            ifb.splat_channels(*px, 0.5, &beauty, &values);
+        }
+        let depth = ifb.channel("Z").unwrap();
+        assert!(depth.iter().all(|z| z.is_finite()));
+        assert!((depth[0] - 0.5).abs() < 0.1);
+        assert!(depth[3] > 0.5 * MISS_DISTANCE);
+    }
+}
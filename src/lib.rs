@@ -13,6 +13,26 @@ extern crate structopt;
 pub mod errors {
     error_chain! {
         errors {
+            CameraLookAtSelf(val: String) {
+                description("Camera look_from and look_at are the same point.")
+                display("Camera look_from and look_at are the same point: {}", val)
+            }
+            InvalidCameraParam(name: String, val: f32, reason: String) {
+                description("Invalid camera parameter.")
+                display("Invalid camera parameter, {} ({}): {}", name, val, reason)
+            }
+            InvalidCheckpoint(path: String, reason: String) {
+                description("Cannot resume from checkpoint.")
+                display("Cannot resume from checkpoint, {}: {}", path, reason)
+            }
+            InvalidCropWindow(val: String, reason: String) {
+                description("Invalid crop window.")
+                display("Invalid crop window, {}: {}", val, reason)
+            }
+            InvalidOptions(option: String, reason: String) {
+                description("Options cannot be used together.")
+                display("Invalid use of --{}: {}", option, reason)
+            }
             InvalidParam(val: f32, t: String) {
                 description("Value invalid")
                 display("Value invalid ({}): {}", t, val)
@@ -21,6 +41,10 @@ pub mod errors {
                 description("Missing command line argument.")
                 display("'{}' must be specified (or have a default).", val)
             }
+            NonFiniteVector(name: String, val: String) {
+                description("Vector has a NaN or infinite component.")
+                display("'{}' must have finite components: {}", name, val)
+            }
             OutOfRange(val: f32, min: f32, max: f32) {
                 description("Value out of range.")
                 display("Value, {}, out of range: [{}, {}]", val, min, max)
@@ -31,42 +55,87 @@ pub mod errors {
             }
         }
         foreign_links {
+            ExrError(exr::error::Error);
             IoError(std::io::Error);
             MiniFBError(minifb::Error);
             ParseIntError(std::num::ParseIntError);
             ParseFloatError(std::num::ParseFloatError);
+            PngEncodingError(png::EncodingError);
             SerdeYamlError(serde_yaml::Error);
         }
     }
-
 }
 
-pub use camera::Camera;
+pub use animation::{CameraAnimation, CameraKeyframe, Interpolation};
+pub use bdpt::{bidirectional, Splat};
+pub use camera::{
+    Camera, CameraSettings, Equirectangular, Fisheye, Importance, Orthographic, Perspective,
+    Projection, Stereo, StereoLayout,
+};
+pub use checkpoint::{load_checkpoint, render_hash, save_checkpoint};
 pub use color::{gradient, Color};
-pub use config::Config;
-pub use fb::IncrementalFrameBuffer;
+pub use config::{Config, CropWindow, FrameRange, TimeLimit};
+pub use debug::{debug_color, RenderMode};
+pub use denoise::{denoise, DenoiseMode, FeatureBuffers};
+pub use distribution::{Distribution1D, Distribution2D};
+pub use environment::{Environment, EnvironmentMap, EnvironmentSample, Gradient};
+pub use fb::{FrameBuffer, IncrementalFrameBuffer, Rect};
+pub use filter::{Filter, FilterKind};
+pub use hdr::HdrImage;
 pub use hittest::{HitRecord, HitTest};
-pub use material::{Dielectric, Lambertian, Material, Metal};
+pub use integrator::{aov_channel_count, aov_channel_names, radiance, sample_aovs, Integrator};
+pub use light::{DirectionalLight, Light, LightEmission, LightSample, PointLight, SpotLight};
+pub use material::{Dielectric, Dispersion, Lambertian, Material, Metal};
+pub use mlt::metropolis;
+pub use photon::{photon_mapping, PhotonMap};
+pub use preview::{ambient_occlusion, whitted};
 pub use ray::Ray;
+pub use sampler::{pass_seed, sample_seed, wants_aovs, Sampler};
+pub use scene::Scene;
 pub use screen::Screen;
+pub use sky::PreethamSky;
+pub use spectral::spectral_radiance;
 pub use sphere::Sphere;
-pub use unit_random::unit_random;
-pub use util::random_in_unit_sphere;
-pub use vec3::{dot, Vec3};
+pub use stats::{
+    count_max_depth_termination, count_path, count_ray, rays_traced, thread_stats, RayKind, Stats,
+    StatsFormat,
+};
+pub use unit_random::{seed_unit_random, unit_random};
+pub use util::{random_in_unit_disk, random_in_unit_sphere, range_check};
+pub use vec3::{cross, dot, orthonormal_basis, Vec3};
 pub use world::{load_world, World, Worlds};
 
-pub use pg::Progress;
+pub use pg::{format_duration, Progress, ProgressFormat};
 
+mod animation;
+mod bdpt;
 mod camera;
+mod checkpoint;
 mod color;
 mod config;
+mod debug;
+mod denoise;
+mod distribution;
+mod environment;
 mod fb;
+mod filter;
+mod hdr;
 mod hittest;
+mod integrator;
+mod light;
 mod material;
+mod mlt;
 mod pg;
+mod photon;
+mod preview;
 mod ray;
+mod sampler;
+mod scene;
 mod screen;
+mod sky;
+mod spectral;
 mod sphere;
+mod stats;
 mod unit_random;
 mod util;
 mod vec3;
@@ -0,0 +1,343 @@
+use std::convert::TryFrom;
+use std::f32;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::*;
+use crate::unit_random::unit_random;
+use crate::util::uniform_sphere;
+use crate::vec3::{dot, orthonormal_basis, Vec3};
+
+pub struct LightSample {
+    // Unit vector from the lit point towards the light.
+    pub direction: Vec3,
+    // Distance to the light, f32::MAX for lights at infinity.
+    pub distance: f32,
+    // Radiance arriving at the lit point, including any falloff.
+    pub radiance: Vec3,
+}
+
+// A ray of light leaving a light, for tracing light paths.
+pub struct LightEmission {
+    pub origin: Vec3,
+    // Unit vector.
+    pub direction: Vec3,
+    // Radiant intensity in direction.
+    pub intensity: Vec3,
+    // Probability density of choosing direction, with respect to solid angle.
+    pub pdf: f32,
+}
+
+// Lights that exist at a single point or direction. Rays can never hit them, so they are only
+// found by shadow rays from the points they illuminate.
+#[typetag::serde(tag = "type")]
+pub trait Light {
+    fn sample(&self, point: &Vec3) -> Result<Option<LightSample>>;
+
+    // None for lights at infinity.
+    fn position(&self) -> Option<Vec3> {
+        None
+    }
+
+    // Lights with a position can also start light paths.
+    fn emit(&self) -> Option<LightEmission> {
+        None
+    }
+
+    // The pdf with which emit() would choose direction.
+    fn emission_pdf(&self, _direction: &Vec3) -> f32 {
+        0.0
+    }
+}
+
+// Shines equally in all directions. intensity is the radiant intensity (power per steradian).
+#[derive(Serialize, Deserialize)]
+pub struct PointLight {
+    position: Vec3,
+    intensity: Vec3,
+}
+
+impl PointLight {
+    pub fn new(position: Vec3, intensity: Vec3) -> PointLight {
+        PointLight {
+            position,
+            intensity,
+        }
+    }
+}
+
+#[typetag::serde]
+impl Light for PointLight {
+    fn sample(&self, point: &Vec3) -> Result<Option<LightSample>> {
+        let to_light = self.position - point;
+        let distance = to_light.length();
+        if distance == 0.0 {
+            return Ok(None);
+        }
+
+        Ok(Some(LightSample {
+            direction: to_light / distance,
+            distance,
+            radiance: self.intensity / (distance * distance),
+        }))
+    }
+
+    fn position(&self) -> Option<Vec3> {
+        Some(self.position)
+    }
+
+    fn emit(&self) -> Option<LightEmission> {
+        Some(LightEmission {
+            origin: self.position,
+            direction: uniform_sphere(unit_random(), unit_random()),
+            intensity: self.intensity,
+            pdf: 1.0 / (4.0 * f32::consts::PI),
+        })
+    }
+
+    fn emission_pdf(&self, _direction: &Vec3) -> f32 {
+        1.0 / (4.0 * f32::consts::PI)
+    }
+}
+
+// A point light restricted to a cone. Full intensity out to falloff_start degrees from
+// direction, fading smoothly to nothing at cone_angle degrees.
+#[derive(Serialize, Deserialize)]
+#[serde(try_from = "SpotLightFields")]
+pub struct SpotLight {
+    position: Vec3,
+    direction: Vec3,
+    intensity: Vec3,
+    cone_angle: f32,
+    falloff_start: f32,
+}
+
+// A SpotLight as written in a scene file, checked by SpotLight::new when it is loaded.
+#[derive(Deserialize)]
+struct SpotLightFields {
+    position: Vec3,
+    direction: Vec3,
+    intensity: Vec3,
+    cone_angle: f32,
+    falloff_start: f32,
+}
+
+impl TryFrom<SpotLightFields> for SpotLight {
+    type Error = Error;
+
+    fn try_from(fields: SpotLightFields) -> Result<SpotLight> {
+        SpotLight::new(
+            fields.position,
+            fields.direction,
+            fields.intensity,
+            fields.cone_angle,
+            fields.falloff_start,
+        )
+    }
+}
+
+impl SpotLight {
+    pub fn new(
+        position: Vec3,
+        direction: Vec3,
+        intensity: Vec3,
+        cone_angle: f32,
+        falloff_start: f32,
+    ) -> Result<SpotLight> {
+        if !(cone_angle > 0.0 && cone_angle <= 180.0) {
+            return Err(ErrorKind::InvalidParam(
+                cone_angle,
+                "cone_angle must be > 0 and <= 180".into(),
+            )
+            .into());
+        }
+        if falloff_start > cone_angle {
+            return Err(ErrorKind::InvalidParam(
+                falloff_start,
+                "falloff_start must be <= cone_angle".into(),
+            )
+            .into());
+        }
+        Ok(SpotLight {
+            position,
+            direction: direction.unit_vector()?,
+            intensity,
+            cone_angle,
+            falloff_start,
+        })
+    }
+
+    fn falloff(&self, cos_theta: f32) -> f32 {
+        let cos_total = f32::cos(self.cone_angle.to_radians());
+        let cos_start = f32::cos(self.falloff_start.to_radians());
+        if cos_theta < cos_total {
+            0.0
+        } else if cos_theta >= cos_start {
+            1.0
+        } else {
+            let delta = (cos_theta - cos_total) / (cos_start - cos_total);
+            delta * delta * (3.0 - 2.0 * delta)
+        }
+    }
+}
+
+#[typetag::serde]
+impl Light for SpotLight {
+    fn sample(&self, point: &Vec3) -> Result<Option<LightSample>> {
+        let to_light = self.position - point;
+        let distance = to_light.length();
+        if distance == 0.0 {
+            return Ok(None);
+        }
+
+        let direction = to_light / distance;
+        let cos_theta = -dot(&direction, &self.direction);
+        let falloff = self.falloff(cos_theta);
+        if falloff == 0.0 {
+            return Ok(None);
+        }
+
+        Ok(Some(LightSample {
+            direction,
+            distance,
+            radiance: falloff / (distance * distance) * self.intensity,
+        }))
+    }
+
+    fn position(&self) -> Option<Vec3> {
+        Some(self.position)
+    }
+
+    // Directions are chosen uniformly within the cone.
+    fn emit(&self) -> Option<LightEmission> {
+        let axis = self.direction;
+        let cos_total = f32::cos(self.cone_angle.to_radians());
+        let cos_theta = 1.0 - unit_random() * (1.0 - cos_total);
+        let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta * cos_theta));
+        let phi = 2.0 * f32::consts::PI * unit_random();
+        let (u, v) = orthonormal_basis(&axis);
+        Some(LightEmission {
+            origin: self.position,
+            direction: sin_theta * f32::cos(phi) * u
+                + sin_theta * f32::sin(phi) * v
+                + cos_theta * axis,
+            intensity: self.falloff(cos_theta) * self.intensity,
+            pdf: 1.0 / (2.0 * f32::consts::PI * (1.0 - cos_total)),
+        })
+    }
+
+    fn emission_pdf(&self, direction: &Vec3) -> f32 {
+        let cos_total = f32::cos(self.cone_angle.to_radians());
+        if dot(direction, &self.direction) >= cos_total {
+            1.0 / (2.0 * f32::consts::PI * (1.0 - cos_total))
+        } else {
+            0.0
+        }
+    }
+}
+
+// Light from infinitely far away, all travelling in the same direction, like sunlight.
+// irradiance is measured on a surface facing the light.
+#[derive(Serialize, Deserialize)]
+pub struct DirectionalLight {
+    direction: Vec3,
+    irradiance: Vec3,
+}
+
+impl DirectionalLight {
+    pub fn new(direction: Vec3, irradiance: Vec3) -> Result<DirectionalLight> {
+        Ok(DirectionalLight {
+            direction: direction.unit_vector()?,
+            irradiance,
+        })
+    }
+}
+
+#[typetag::serde]
+impl Light for DirectionalLight {
+    fn sample(&self, _point: &Vec3) -> Result<Option<LightSample>> {
+        Ok(Some(LightSample {
+            direction: -self.direction.unit_vector()?,
+            distance: f32::MAX,
+            radiance: self.irradiance,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn white() -> Vec3 {
+        Vec3::cartesian(1.0, 1.0, 1.0)
+    }
+
+    #[test]
+    fn test_point_falloff() {
+        let light = PointLight::new(Vec3::cartesian(0.0, 4.0, 0.0), white());
+
+        let near = light
+            .sample(&Vec3::cartesian(0.0, 2.0, 0.0))
+            .unwrap()
+            .unwrap();
+        let far = light.sample(&Vec3::origin()).unwrap().unwrap();
+        assert_eq!(Vec3::cartesian(0.0, 1.0, 0.0), far.direction);
+        assert_eq!(4.0, far.distance);
+        assert_eq!(0.25 * white(), near.radiance);
+        assert_eq!(white() / 16.0, far.radiance);
+        assert_eq!(4.0 * far.radiance, near.radiance);
+    }
+
+    #[test]
+    fn test_spot_falloff() {
+        let light = SpotLight::new(
+            Vec3::cartesian(0.0, 2.0, 0.0),
+            Vec3::cartesian(0.0, -1.0, 0.0),
+            white(),
+            45.0,
+            30.0,
+        )
+        .unwrap();
+
+        let center = light.sample(&Vec3::origin()).unwrap().unwrap();
+        assert_eq!(0.25 * white(), center.radiance);
+
+        // Inside the falloff region, dimmer than an unobstructed point light.
+        let edge = light
+            .sample(&Vec3::cartesian(1.5, 0.0, 0.0))
+            .unwrap()
+            .unwrap();
+        let point = PointLight::new(Vec3::cartesian(0.0, 2.0, 0.0), white());
+        let unattenuated = point
+            .sample(&Vec3::cartesian(1.5, 0.0, 0.0))
+            .unwrap()
+            .unwrap();
+        assert!(edge.radiance.x() > 0.0 && edge.radiance.x() < unattenuated.radiance.x());
+
+        assert!(light
+            .sample(&Vec3::cartesian(3.0, 0.0, 0.0))
+            .unwrap()
+            .is_none());
+
+        for cone_angle in &[0.0, -10.0, 190.0, f32::NAN] {
+            assert!(SpotLight::new(Vec3::origin(), white(), white(), *cone_angle, 0.0).is_err());
+        }
+
+        // Scene files are checked the same way.
+        let spot = "{type: SpotLight, position: [0, 2, 0], direction: [0, -1, 0], \
+                    intensity: [1, 1, 1], cone_angle: 30, falloff_start: 45}";
+        assert!(serde_yaml::from_str::<Box<dyn Light>>(spot).is_err());
+        let spot = spot.replace("falloff_start: 45", "falloff_start: 20");
+        assert!(serde_yaml::from_str::<Box<dyn Light>>(&spot).is_ok());
+    }
+
+    #[test]
+    fn test_directional() {
+        let light = DirectionalLight::new(Vec3::cartesian(0.0, -2.0, 0.0), white()).unwrap();
+        for point in &[Vec3::origin(), Vec3::cartesian(100.0, -50.0, 3.0)] {
+            let sample = light.sample(point).unwrap().unwrap();
+            assert_eq!(Vec3::cartesian(0.0, 1.0, 0.0), sample.direction);
+            assert_eq!(white(), sample.radiance);
+        }
+    }
+}
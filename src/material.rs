@@ -1,16 +1,55 @@
+use std::convert::TryFrom;
+
 use serde::{Deserialize, Serialize};
 
 use crate::color::Color;
 use crate::errors::*;
 use crate::hittest::HitRecord;
 use crate::ray::Ray;
+use crate::spectral::{MAX_WAVELENGTH, MIN_WAVELENGTH};
 use crate::unit_random::unit_random;
-use crate::util::{if_then, random_in_unit_sphere};
+use crate::util::{if_then, random_cosine_direction};
 use crate::vec3::{dot, Vec3};
 
 #[typetag::serde(tag = "type")]
 pub trait Material {
     fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Result<Option<(Ray, Vec3)>>;
+
+    // Like scatter, for light of a single wavelength (in nanometers). Only surfaces whose
+    // behavior depends on the wavelength need to override this.
+    fn scatter_wavelength(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        _wavelength: f32,
+    ) -> Result<Option<(Ray, Vec3)>> {
+        self.scatter(ray, hit_record)
+    }
+
+    // Whether the surface sends light of different wavelengths in different directions, as
+    // glass with dispersion does.
+    fn is_dispersive(&self) -> bool {
+        false
+    }
+
+    // The BSDF times the cosine term for light arriving from `direction` (a unit vector).
+    // Materials that only scatter in discrete directions (mirrors, glass) return None, since
+    // light sampling can never find those directions.
+    fn bsdf(&self, _hit_record: &HitRecord, _direction: &Vec3) -> Option<Vec3> {
+        None
+    }
+
+    // Every direction that a mirror or glass surface sends the light arriving along ray, with
+    // the fraction of the light sent each way, for tracing them all without randomness. Empty
+    // for other surfaces.
+    fn specular(&self, _ray: &Ray, _hit_record: &HitRecord) -> Result<Vec<(Ray, Vec3)>> {
+        Ok(vec![])
+    }
+
+    // The color of the surface, for debugging renders.
+    fn albedo(&self) -> Vec3 {
+        Vec3::cartesian(1.0, 1.0, 1.0)
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -29,10 +68,22 @@ impl Lambertian {
 #[typetag::serde]
 impl Material for Lambertian {
     fn scatter(&self, _: &Ray, hit_record: &HitRecord) -> Result<Option<(Ray, Vec3)>> {
-        let target = hit_record.point + hit_record.normal + random_in_unit_sphere();
-        let scattered = Ray::new(hit_record.point, target - hit_record.point);
+        // Cosine-distributed, so that the attenuation (bsdf * cos / pdf) is just the albedo.
+        let scattered = Ray::new(
+            hit_record.point,
+            random_cosine_direction(&hit_record.normal),
+        );
         Ok(Some((scattered, self.albedo)))
     }
+
+    fn bsdf(&self, hit_record: &HitRecord, direction: &Vec3) -> Option<Vec3> {
+        let cosine = f32::max(dot(&hit_record.normal, direction), 0.0);
+        Some(cosine / std::f32::consts::PI * self.albedo)
+    }
+
+    fn albedo(&self) -> Vec3 {
+        self.albedo
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -63,16 +114,121 @@ impl Material for Metal {
             || Some((scattered, attenuation)),
         ))
     }
+
+    fn specular(&self, ray: &Ray, hit_record: &HitRecord) -> Result<Vec<(Ray, Vec3)>> {
+        Ok(self.scatter(ray, hit_record)?.into_iter().collect())
+    }
+
+    fn albedo(&self) -> Vec3 {
+        self.albedo
+    }
+}
+
+// How the refractive index of glass changes with the wavelength of the light, with wavelengths
+// in micrometers.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", try_from = "DispersionFields")]
+pub enum Dispersion {
+    // n = a + b / wavelength^2, a good fit for most glasses across visible light.
+    Cauchy { a: f32, b: f32 },
+    // n^2 = 1 + sum of b[i] wavelength^2 / (wavelength^2 - c[i]), as given for optical glasses
+    // by their makers.
+    Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+// A Dispersion as written in a scene file, checked by Dispersion::check when it is loaded.
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+enum DispersionFields {
+    Cauchy { a: f32, b: f32 },
+    Sellmeier { b: [f32; 3], c: [f32; 3] },
+}
+
+impl TryFrom<DispersionFields> for Dispersion {
+    type Error = Error;
+
+    fn try_from(fields: DispersionFields) -> Result<Dispersion> {
+        let dispersion = match fields {
+            DispersionFields::Cauchy { a, b } => Dispersion::Cauchy { a, b },
+            DispersionFields::Sellmeier { b, c } => Dispersion::Sellmeier { b, c },
+        };
+        dispersion.check()?;
+        Ok(dispersion)
+    }
+}
+
+impl Dispersion {
+    // Makes sure that the index is finite and positive at every wavelength that is traced.
+    fn check(&self) -> Result<()> {
+        if let Dispersion::Sellmeier { b, c } = self {
+            // Each term has a pole where the wavelength squared is c[i].
+            let min = MIN_WAVELENGTH / 1000.0;
+            let max = MAX_WAVELENGTH / 1000.0;
+            for (b, c) in b.iter().zip(c.iter()) {
+                if *b != 0.0 && *c >= min * min && *c <= max * max {
+                    return Err(ErrorKind::InvalidParam(
+                        *c,
+                        "c must not be the square of a visible wavelength".into(),
+                    )
+                    .into());
+                }
+            }
+        }
+        let mut wavelength = MIN_WAVELENGTH;
+        while wavelength <= MAX_WAVELENGTH {
+            let refractive_index = self.refractive_index(wavelength);
+            if !(refractive_index.is_finite() && refractive_index > 0.0) {
+                return Err(ErrorKind::InvalidParam(
+                    wavelength,
+                    "dispersion gives no refractive index at this wavelength".into(),
+                )
+                .into());
+            }
+            wavelength += 1.0;
+        }
+        Ok(())
+    }
+
+    pub fn refractive_index(&self, wavelength: f32) -> f32 {
+        let micrometers = wavelength / 1000.0;
+        let squared = micrometers * micrometers;
+        match self {
+            Dispersion::Cauchy { a, b } => a + b / squared,
+            Dispersion::Sellmeier { b, c } => {
+                let sum: f32 = b
+                    .iter()
+                    .zip(c.iter())
+                    .map(|(b, c)| b * squared / (squared - c))
+                    .sum();
+                f32::sqrt(1.0 + sum)
+            }
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct Dielectric {
+    // The index used when rendering in RGB, and by spectral rendering if there is no dispersion.
     refractive_index: f32,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dispersion: Option<Dispersion>,
 }
 
 impl Dielectric {
     pub fn new(refractive_index: f32) -> Dielectric {
-        Dielectric { refractive_index }
+        Dielectric {
+            refractive_index,
+            dispersion: None,
+        }
+    }
+
+    pub fn with_dispersion(refractive_index: f32, dispersion: Dispersion) -> Result<Dielectric> {
+        dispersion.check()?;
+        Ok(Dielectric {
+            refractive_index,
+            dispersion: Some(dispersion),
+        })
     }
 }
 
@@ -91,20 +247,26 @@ fn schlick(cosine: f32, ref_idx: f32) -> f32 {
     r0sq + (1.0 - r0sq) * f32::powi(1.0 - cosine, 5)
 }
 
-#[typetag::serde]
-impl Material for Dielectric {
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Result<Option<(Ray, Vec3)>> {
+impl Dielectric {
+    // The chance that light arriving along ray is reflected rather than refracted, and the
+    // refracted direction (None for total internal reflection).
+    fn split(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        refractive_index: f32,
+    ) -> Result<(f32, Option<Vec3>)> {
         let dotp = dot(ray.direction(), &hit_record.normal);
         let (outward_normal, ni_over_nt, cosine) = if dotp > 0.0 {
             (
                 -hit_record.normal,
-                self.refractive_index,
-                self.refractive_index * dotp / ray.direction().length(),
+                refractive_index,
+                refractive_index * dotp / ray.direction().length(),
             )
         } else {
             (
                 hit_record.normal,
-                1.0 / self.refractive_index,
+                1.0 / refractive_index,
                 -dotp / ray.direction().length(),
             )
         };
@@ -113,11 +275,20 @@ impl Material for Dielectric {
         let reflect_prob =
             if let Some(refracted_val) = refract(ray.direction(), &outward_normal, ni_over_nt)? {
                 refracted = Some(refracted_val);
-                schlick(cosine, self.refractive_index)
+                schlick(cosine, refractive_index)
             } else {
                 1.0
             };
+        Ok((reflect_prob, refracted))
+    }
 
+    fn scatter_with_index(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        refractive_index: f32,
+    ) -> Result<Option<(Ray, Vec3)>> {
+        let (reflect_prob, refracted) = self.split(ray, hit_record, refractive_index)?;
         let scattered = if unit_random() < reflect_prob {
             Ray::new(
                 hit_record.point,
@@ -134,3 +305,43 @@ impl Material for Dielectric {
         Ok(Some((scattered, attenuation)))
     }
 }
+
+#[typetag::serde]
+impl Material for Dielectric {
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Result<Option<(Ray, Vec3)>> {
+        self.scatter_with_index(ray, hit_record, self.refractive_index)
+    }
+
+    fn scatter_wavelength(
+        &self,
+        ray: &Ray,
+        hit_record: &HitRecord,
+        wavelength: f32,
+    ) -> Result<Option<(Ray, Vec3)>> {
+        let refractive_index = match &self.dispersion {
+            Some(dispersion) => dispersion.refractive_index(wavelength),
+            None => self.refractive_index,
+        };
+        self.scatter_with_index(ray, hit_record, refractive_index)
+    }
+
+    fn is_dispersive(&self) -> bool {
+        self.dispersion.is_some()
+    }
+
+    fn specular(&self, ray: &Ray, hit_record: &HitRecord) -> Result<Vec<(Ray, Vec3)>> {
+        let (reflect_prob, refracted) = self.split(ray, hit_record, self.refractive_index)?;
+        let reflected = Ray::new(
+            hit_record.point,
+            reflect(ray.direction(), &hit_record.normal),
+        );
+        let mut rays = vec![(reflected, reflect_prob * Vec3::cartesian(1.0, 1.0, 1.0))];
+        if let Some(refracted) = refracted {
+            rays.push((
+                Ray::new(hit_record.point, refracted),
+                (1.0 - reflect_prob) * Vec3::cartesian(1.0, 1.0, 1.0),
+            ));
+        }
+        Ok(rays)
+    }
+}
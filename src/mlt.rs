@@ -0,0 +1,190 @@
+use std::f32;
+
+use crate::bdpt::Splat;
+use crate::camera::Camera;
+use crate::color::Color;
+use crate::config::Config;
+use crate::distribution::Distribution1D;
+use crate::environment::Environment;
+use crate::errors::*;
+use crate::integrator::radiance;
+use crate::scene::Scene;
+use crate::unit_random::{unit_random, with_primary_samples};
+use crate::vec3::Vec3;
+
+// Independent Markov chains run for each pass, each starting from a bootstrap sample.
+const CHAINS: usize = 64;
+
+// How often a mutation is a large step: a completely new sample, which keeps the chains from
+// getting stuck in one bright region.
+const LARGE_STEP_PROBABILITY: f32 = 0.3;
+
+// The range of the small step perturbations of Kelemen et al.
+const SMALL_STEP_MIN: f32 = 1.0 / 1024.0;
+const SMALL_STEP_MAX: f32 = 1.0 / 64.0;
+
+// A path through the scene, and what it carries to the camera.
+struct State {
+    // The point in primary sample space that the path was traced from.
+    values: Vec<f32>,
+    splat: Splat,
+    luminance: f32,
+}
+
+impl State {
+    fn trace(
+        values: Vec<f32>,
+        camera: &dyn Camera,
+        config: &Config,
+        scene: &Scene,
+        environment: &dyn Environment,
+    ) -> Result<State> {
+        let mut values = values;
+        let splat = with_primary_samples(&mut values, || -> Result<Splat> {
+            let u = unit_random();
+            let v = unit_random();
+            let radiance = match camera.get_ray(u, v) {
+                Some(ray) => radiance(&ray, config, scene, environment)?,
+                None => Vec3::origin(),
+            };
+            Ok(Splat { u, v, radiance })
+        })?;
+        let luminance =
+            Color::luminance_of(splat.radiance.x(), splat.radiance.y(), splat.radiance.z());
+        Ok(State {
+            values,
+            splat,
+            // Paths carrying non-finite light would stop a chain for good.
+            luminance: if luminance.is_finite() {
+                f32::max(luminance, 0.0)
+            } else {
+                0.0
+            },
+        })
+    }
+}
+
+// Renders one pass of the image with primary sample space Metropolis light transport (Kelemen
+// et al.): each path the path tracer might trace is a point in the space of the random numbers
+// it uses, and Markov chains wander through that space, visiting paths in proportion to their
+// brightness. Each step either nudges every random number a little (a small step), which
+// explores the neighborhood of a bright but hard to find path, or starts afresh (a large step).
+//
+// The chains only find the relative brightness of the image, so it is scaled by the mean
+// brightness of one path for each pixel (the bootstrap), from which the chains also start.
+//
+// Calls splat with the light found for each of the --mutations_per_pixel mutations per pixel,
+// and returns the number of mutations. The image is the sum of the splats in each pixel, times
+// the number of pixels over the number of mutations.
+pub fn metropolis<F>(
+    camera: &dyn Camera,
+    config: &Config,
+    scene: &Scene,
+    environment: &dyn Environment,
+    mut splat: F,
+) -> Result<u64>
+where
+    F: FnMut(&Splat),
+{
+    let pixels = config.screen_width * config.screen_height;
+    let mut bootstrap = Vec::with_capacity(pixels);
+    for _ in 0..pixels {
+        bootstrap.push(State::trace(vec![], camera, config, scene, environment)?);
+    }
+    let distribution = Distribution1D::new(bootstrap.iter().map(|s| s.luminance).collect());
+    let brightness = distribution.integral();
+    if brightness == 0.0 {
+        return Ok(0);
+    }
+
+    let mutations_per_chain = pixels * config.mutations_per_pixel as usize / CHAINS;
+    for _ in 0..CHAINS {
+        let (_, _, start) = distribution.sample(unit_random());
+        let mut current = State {
+            values: bootstrap[start].values.clone(),
+            ..bootstrap[start]
+        };
+        for _ in 0..mutations_per_chain {
+            let values = if unit_random() < LARGE_STEP_PROBABILITY {
+                vec![]
+            } else {
+                current.values.iter().map(|value| perturb(*value)).collect()
+            };
+            let proposed = State::trace(values, camera, config, scene, environment)?;
+            let accept = if current.luminance > 0.0 {
+                f32::min(1.0, proposed.luminance / current.luminance)
+            } else {
+                1.0
+            };
+
+            // Both states are splatted, weighted by their chance of being next, which spreads
+            // the light more evenly than splatting only the state the chain moves to.
+            if proposed.luminance > 0.0 {
+                splat(&Splat {
+                    radiance: (accept * brightness / proposed.luminance) * proposed.splat.radiance,
+                    ..proposed.splat
+                });
+            }
+            if current.luminance > 0.0 {
+                splat(&Splat {
+                    radiance: ((1.0 - accept) * brightness / current.luminance)
+                        * current.splat.radiance,
+                    ..current.splat
+                });
+            }
+
+            if unit_random() < accept {
+                current = proposed;
+            }
+        }
+    }
+    Ok((mutations_per_chain * CHAINS) as u64)
+}
+
+// A small step: moves value by an amount between SMALL_STEP_MIN and SMALL_STEP_MAX, most
+// likely a small one, wrapping around [0, 1).
+fn perturb(value: f32) -> f32 {
+    let range = f32::ln(SMALL_STEP_MAX / SMALL_STEP_MIN);
+    let step = SMALL_STEP_MAX * f32::exp(-range * unit_random());
+    let moved = if unit_random() < 0.5 {
+        value + step
+    } else {
+        value - step
+    };
+    let wrapped = moved - moved.floor();
+    // Rounding can leave exactly 1.0, which is outside the range.
+    if wrapped >= 1.0 {
+        0.0
+    } else {
+        wrapped
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::unit_random::seed_unit_random;
+
+    #[test]
+    fn test_primary_samples() {
+        seed_unit_random(5);
+        let mut values = vec![0.25, 0.5];
+        let drawn = with_primary_samples(&mut values, || {
+            (0..4).map(|_| unit_random()).collect::<Vec<_>>()
+        });
+        // The given numbers come first, then fresh ones, which are kept for next time.
+        assert_eq!(vec![0.25, 0.5], drawn[..2].to_vec());
+        assert_eq!(drawn, values);
+        let replayed = with_primary_samples(&mut values, || {
+            (0..4).map(|_| unit_random()).collect::<Vec<_>>()
+        });
+        assert_eq!(drawn, replayed);
+
+        for value in &[0.0, 0.001, 0.5, 0.999] {
+            let moved = perturb(*value);
+            assert!((0.0..1.0).contains(&moved));
+            let distance = f32::min((moved - value).abs(), 1.0 - (moved - value).abs());
+            assert!(distance <= SMALL_STEP_MAX + 1.0e-6);
+        }
+    }
+}
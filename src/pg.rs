@@ -1,10 +1,52 @@
 use std::io::{self, Write};
+use std::str::FromStr;
 use std::time::{Duration, Instant};
 
+use crate::errors::*;
+
+// How progress is reported: as a status line on stderr, as JSON objects on stdout (one per
+// line, for scripts), or not at all.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ProgressFormat {
+    Text,
+    Json,
+    None,
+}
+
+impl FromStr for ProgressFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ProgressFormat> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(ProgressFormat::Text),
+            "json" => Ok(ProgressFormat::Json),
+            "none" => Ok(ProgressFormat::None),
+            _ => Err(ErrorKind::ParseError(
+                s.to_string(),
+                "Must be 'text', 'json', or 'none'.".to_string(),
+            )
+            .into()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Progress {
     value: u64,
     max: u64, // Max of zero means just to display the count.
+    deadline: Option<Instant>,
+
+    // A second level of progress within the current step, e.g. rows within a pass.
+    sub_value: u64,
+    sub_max: u64,
+
+    // Work done since the meter started, for the throughput.
+    samples: u64,
+    rays: u64,
+
+    format: ProgressFormat,
+    start_time: Instant,
+    start_value: u64,
 
     last_line_time: Option<Instant>,
     last_line_len: usize,
@@ -14,22 +56,62 @@ impl Progress {
     pub fn new(max: u64) -> Progress {
         Progress {
             max,
+            deadline: None,
             value: 0,
+            sub_value: 0,
+            sub_max: 0,
+            samples: 0,
+            rays: 0,
+            format: ProgressFormat::Text,
+            start_time: Instant::now(),
+            start_value: 0,
             last_line_len: 0,
             last_line_time: None,
         }
     }
 
+    // Counts up with no maximum, showing the time left until limit has passed instead.
+    pub fn with_time_limit(limit: Duration) -> Progress {
+        let mut progress = Progress::new(0);
+        progress.deadline = Some(progress.start_time + limit);
+        progress
+    }
+
+    pub fn set_format(&mut self, format: ProgressFormat) {
+        self.format = format;
+    }
+
+    // Starts counting at value, for work that is resumed part way through. The time remaining
+    // is estimated from the progress made since then.
+    pub fn set_start(&mut self, value: u64) {
+        self.value = value;
+        self.start_value = value;
+    }
+
     pub fn inc(&mut self) {
         self.value += 1;
+        self.sub_value = 0;
         self.check_update();
     }
 
     pub fn set(&mut self, val: u64) {
         self.value = val;
+        self.sub_value = 0;
+        self.check_update();
+    }
+
+    pub fn set_sub(&mut self, val: u64, max: u64) {
+        self.sub_value = val;
+        self.sub_max = max;
         self.check_update();
     }
 
+    // Adds to the number of samples taken and rays traced.
+    pub fn add_work(&mut self, samples: u64, rays: u64) {
+        self.samples += samples;
+        self.rays += rays;
+    }
+
     pub fn check_update(&mut self) {
         if let Some(last) = self.last_line_time {
             if last.elapsed() > Duration::from_millis(500) {
@@ -45,17 +127,207 @@ impl Progress {
         self.update();
     }
 
+    // Time left after elapsed, extrapolated from the progress so far, or until the deadline.
+    fn remaining(&self, elapsed: Duration) -> Option<Duration> {
+        if let Some(deadline) = self.deadline {
+            return Some(deadline.saturating_duration_since(self.start_time + elapsed));
+        }
+
+        let mut done = self.value as f64;
+        if self.sub_max > 0 {
+            done += self.sub_value as f64 / self.sub_max as f64;
+        }
+        let progress = done - self.start_value as f64;
+        if self.max == 0 || progress <= 0.0 {
+            return None;
+        }
+        let left = f64::max(self.max as f64 - done, 0.0);
+        Some(elapsed.mul_f64(left / progress))
+    }
+
+    fn rate(count: u64, elapsed: Duration) -> f64 {
+        let seconds = elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            count as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+
     fn update(&mut self) {
         self.last_line_time = Some(Instant::now());
-        let line = format!("{}/{}", self.value, self.max);
+        match self.format {
+            ProgressFormat::Text => self.update_text(),
+            ProgressFormat::Json => self.update_json(false),
+            ProgressFormat::None => {}
+        }
+    }
+
+    fn update_text(&mut self) {
+        let line = self.text_line(self.start_time.elapsed());
+        // Pad to cover the previous line, which may have been longer.
+        eprint!("\r{:1$}", line, self.last_line_len);
         self.last_line_len = line.len();
-        eprint!("\r{}", line)
+    }
+
+    // The status line shown after elapsed, e.g. "3/10 (40/240), 1m02s, 2m24s left, ...".
+    fn text_line(&self, elapsed: Duration) -> String {
+        let mut line = if self.max == 0 {
+            format!("{}", self.value)
+        } else {
+            format!("{}/{}", self.value, self.max)
+        };
+        if self.sub_max > 0 {
+            line += &format!(" ({}/{})", self.sub_value, self.sub_max);
+        }
+        line += &format!(", {}", format_duration(elapsed));
+        if let Some(remaining) = self.remaining(elapsed) {
+            line += &format!(", {} left", format_duration(remaining));
+        }
+        if self.samples > 0 {
+            line += &format!(
+                ", {} samples/s, {} rays/s",
+                format_rate(Progress::rate(self.samples, elapsed)),
+                format_rate(Progress::rate(self.rays, elapsed))
+            );
+        }
+        line
+    }
+
+    fn update_json(&self, done: bool) {
+        let line = self.json_line(self.start_time.elapsed(), done);
+        // A reader that goes away should not stop the work being reported on.
+        let mut stdout = io::stdout();
+        let _ = writeln!(stdout, "{}", line).and_then(|_| stdout.flush());
+    }
+
+    // The JSON object reported after elapsed, without a newline.
+    fn json_line(&self, elapsed: Duration, done: bool) -> String {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+        format!(
+            "{{\"value\":{},\"max\":{},\"sub_value\":{},\"sub_max\":{},\"elapsed\":{:.3},\
+             \"remaining\":{},\"samples\":{},\"rays\":{},\"samples_per_sec\":{:.1},\
+             \"rays_per_sec\":{:.1},\"done\":{}}}",
+            self.value,
+            optional(if self.max == 0 {
+                None
+            } else {
+                Some(self.max.to_string())
+            }),
+            self.sub_value,
+            self.sub_max,
+            elapsed.as_secs_f64(),
+            optional(
+                self.remaining(elapsed)
+                    .map(|remaining| format!("{:.3}", remaining.as_secs_f64()))
+            ),
+            self.samples,
+            self.rays,
+            Progress::rate(self.samples, elapsed),
+            Progress::rate(self.rays, elapsed),
+            done
+        )
     }
 
     pub fn finish_and_clear(&mut self) {
-        if self.last_line_time.is_some() {
-            let spaces = " ".repeat(self.last_line_len);
-            eprint!("\r{}\r", spaces);
+        match self.format {
+            ProgressFormat::Text => {
+                if self.last_line_time.is_some() {
+                    let spaces = " ".repeat(self.last_line_len);
+                    eprint!("\r{}\r", spaces);
+                }
+            }
+            ProgressFormat::Json => self.update_json(true),
+            ProgressFormat::None => {}
         }
     }
 }
+
+// Formats d as e.g. "1h02m03s", "2m03s", or "3s".
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, s) => format!("{}h{:02}m{:02}s", h, m, s),
+    }
+}
+
+// Formats a rate with a metric suffix, e.g. "1.2M".
+fn format_rate(rate: f64) -> String {
+    if rate >= 1.0e9 {
+        format!("{:.1}G", rate / 1.0e9)
+    } else if rate >= 1.0e6 {
+        format!("{:.1}M", rate / 1.0e6)
+    } else if rate >= 1.0e3 {
+        format!("{:.1}k", rate / 1.0e3)
+    } else {
+        format!("{:.0}", rate)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lines() {
+        // Resumed at step 2 of 10 and now half way through step 4, so 2.5 steps took 10s and
+        // the 5.5 left will take 22s.
+        let mut progress = Progress::new(10);
+        progress.set_format(ProgressFormat::None);
+        progress.set_start(2);
+        progress.set(4);
+        progress.set_sub(1, 2);
+        progress.add_work(1000, 5000);
+        let elapsed = Duration::from_secs(10);
+
+        assert_eq!(Some(Duration::from_secs(22)), progress.remaining(elapsed));
+        assert_eq!(
+            "4/10 (1/2), 10s, 22s left, 100 samples/s, 500 rays/s",
+            progress.text_line(elapsed)
+        );
+        assert_eq!(
+            "{\"value\":4,\"max\":10,\"sub_value\":1,\"sub_max\":2,\"elapsed\":10.000,\
+             \"remaining\":22.000,\"samples\":1000,\"rays\":5000,\"samples_per_sec\":100.0,\
+             \"rays_per_sec\":500.0,\"done\":false}",
+            progress.json_line(elapsed, false)
+        );
+
+        // With no progress yet there is nothing to extrapolate from.
+        let mut progress = Progress::new(10);
+        progress.set_format(ProgressFormat::None);
+        assert_eq!(None, progress.remaining(elapsed));
+    }
+
+    #[test]
+    fn test_time_limit_lines() {
+        let mut progress = Progress::with_time_limit(Duration::from_secs(60));
+        progress.set_format(ProgressFormat::None);
+        progress.set(3);
+        progress.set_sub(40, 240);
+        let elapsed = Duration::from_secs(10);
+
+        assert_eq!("3 (40/240), 10s, 50s left", progress.text_line(elapsed));
+        assert_eq!(
+            "{\"value\":3,\"max\":null,\"sub_value\":40,\"sub_max\":240,\"elapsed\":10.000,\
+             \"remaining\":50.000,\"samples\":0,\"rays\":0,\"samples_per_sec\":0.0,\
+             \"rays_per_sec\":0.0,\"done\":true}",
+            progress.json_line(elapsed, true)
+        );
+        assert_eq!(
+            Some(Duration::from_secs(0)),
+            progress.remaining(Duration::from_secs(90))
+        );
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!("0s", format_duration(Duration::from_millis(999)));
+        assert_eq!("59s", format_duration(Duration::from_secs(59)));
+        assert_eq!("1m00s", format_duration(Duration::from_secs(60)));
+        assert_eq!("10m05s", format_duration(Duration::from_secs(605)));
+        assert_eq!("1h30m00s", format_duration(Duration::from_secs(5400)));
+        assert_eq!("27h46m40s", format_duration(Duration::from_secs(100_000)));
+    }
+}
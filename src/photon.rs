@@ -0,0 +1,542 @@
+use std::cmp::Ordering;
+use std::f32;
+
+use crate::config::Config;
+use crate::environment::Environment;
+use crate::errors::*;
+use crate::hittest::{HitRecord, HitTest};
+use crate::integrator::{is_diffuse, light_contribution};
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::stats::{count_max_depth_termination, count_path, count_ray, RayKind};
+use crate::unit_random::unit_random;
+use crate::util::{random_cosine_direction, random_in_unit_disk};
+use crate::vec3::{dot, orthonormal_basis, Vec3};
+
+// How much of the photon density each pass keeps as the radius shrinks (alpha in Knaus and
+// Zwicker's probabilistic progressive photon mapping). Lower values shrink the radius faster.
+const ALPHA: f32 = 2.0 / 3.0;
+
+// How the light carried by a photon reached the surface it landed on.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum PhotonPath {
+    // Straight from a point or spot light, which shadow rays already find.
+    Direct,
+    // Straight from the environment, which final gather rays find by leaving the scene.
+    Environment,
+    // Only through mirrors and glass.
+    Caustic,
+    // Off at least one diffuse surface.
+    Indirect,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Photon {
+    point: Vec3,
+    // Unit vector back towards where the photon came from.
+    direction: Vec3,
+    power: Vec3,
+    path: PhotonPath,
+}
+
+// The photons left on diffuse surfaces by one pass of light paths, stored as a kd-tree: the
+// photon at the middle of each range of photons splits the rest of the range along its axis.
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    axes: Vec<usize>,
+
+    // How far from a point photons are gathered for this pass.
+    radius: f32,
+}
+
+impl PhotonMap {
+    // Traces --photons light paths from the scene's point and spot lights and from the
+    // environment, for the given pass. The radius shrinks each pass, so that the blur of the
+    // estimates fades away while their noise still averages out.
+    pub fn trace(
+        config: &Config,
+        scene: &Scene,
+        environment: &dyn Environment,
+        pass: u32,
+    ) -> Result<PhotonMap> {
+        let lights = scene
+            .lights
+            .iter()
+            .filter(|light| light.position().is_some())
+            .collect::<Vec<_>>();
+        let bounds = scene.bounding_sphere();
+
+        // Each photon comes from one of the lights or the environment, chosen at random.
+        let emitters = lights.len() + if bounds.is_some() { 1 } else { 0 };
+        let mut photons = vec![];
+        if emitters > 0 {
+            let count = config.photons as f32;
+            let emitter_pdf = 1.0 / emitters as f32;
+            for _ in 0..config.photons {
+                let emitter = usize::min((unit_random() * emitters as f32) as usize, emitters - 1);
+                let emitted = match lights.get(emitter) {
+                    Some(light) => light.emit().map(|emission| {
+                        (
+                            Ray::new(emission.origin, emission.direction),
+                            emission.intensity / emission.pdf,
+                            PhotonPath::Direct,
+                        )
+                    }),
+                    None => bounds.and_then(|(center, radius)| {
+                        environment_photon(environment, &center, radius)
+                            .map(|(ray, power)| (ray, power, PhotonPath::Environment))
+                    }),
+                };
+                if let Some((ray, power, path)) = emitted {
+                    let power = power / (emitter_pdf * count);
+                    trace_photon(scene, ray, power, path, config.max_depth, &mut photons)?;
+                }
+            }
+        }
+
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        Ok(PhotonMap {
+            photons,
+            axes,
+            radius: photon_radius(config.photon_radius, pass),
+        })
+    }
+
+    // Calls f with every photon within radius of point.
+    fn for_each_near<F>(&self, point: &Vec3, f: &mut F)
+    where
+        F: FnMut(&Photon),
+    {
+        self.search(0, self.photons.len(), point, f);
+    }
+
+    fn search<F>(&self, start: usize, end: usize, point: &Vec3, f: &mut F)
+    where
+        F: FnMut(&Photon),
+    {
+        if start >= end {
+            return;
+        }
+        let middle = start + (end - start) / 2;
+        let photon = &self.photons[middle];
+        if (photon.point - *point).squared_length() <= self.radius * self.radius {
+            f(photon);
+        }
+
+        let axis = self.axes[middle];
+        let offset = component(point, axis) - component(&photon.point, axis);
+        if offset <= self.radius {
+            self.search(start, middle, point, f);
+        }
+        if offset >= -self.radius {
+            self.search(middle + 1, end, point, f);
+        }
+    }
+
+    // The light leaving hit_record's surface towards the camera, from the photons near it
+    // that reached it in one of the given ways.
+    fn estimate(&self, hit_record: &HitRecord, paths: &[PhotonPath]) -> Vec3 {
+        let mut sum = Vec3::origin();
+        self.for_each_near(&hit_record.point, &mut |photon| {
+            let cosine = dot(&photon.direction, &hit_record.normal);
+            if cosine <= 0.0 || !paths.contains(&photon.path) {
+                return;
+            }
+            // The BSDF includes the cosine, but photon power is already per unit area.
+            if let Some(bsdf) = hit_record.material.bsdf(hit_record, &photon.direction) {
+                sum = sum + (bsdf / cosine) * photon.power;
+            }
+        });
+        sum / (f32::consts::PI * self.radius * self.radius)
+    }
+}
+
+// The radius for a pass, which shrinks so that its square falls roughly as pass^(ALPHA - 1).
+fn photon_radius(initial: f32, pass: u32) -> f32 {
+    let mut squared = initial * initial;
+    for i in 1..=pass {
+        squared *= (i as f32 - 1.0 + ALPHA) / i as f32;
+    }
+    squared.sqrt()
+}
+
+fn component(v: &Vec3, axis: usize) -> f32 {
+    match axis {
+        0 => v.x(),
+        1 => v.y(),
+        _ => v.z(),
+    }
+}
+
+// Arranges photons into a kd-tree, splitting each range along its widest axis.
+fn build(photons: &mut [Photon], axes: &mut [usize]) {
+    if photons.is_empty() {
+        return;
+    }
+    let mut low = photons[0].point;
+    let mut high = photons[0].point;
+    for photon in photons.iter() {
+        let p = &photon.point;
+        low = Vec3::cartesian(low.x().min(p.x()), low.y().min(p.y()), low.z().min(p.z()));
+        high = Vec3::cartesian(
+            high.x().max(p.x()),
+            high.y().max(p.y()),
+            high.z().max(p.z()),
+        );
+    }
+    let extent = high - low;
+    let axis = (0..3)
+        .max_by(|a, b| {
+            component(&extent, *a)
+                .partial_cmp(&component(&extent, *b))
+                .unwrap_or(Ordering::Equal)
+        })
+        .unwrap_or(0);
+
+    let middle = photons.len() / 2;
+    photons.select_nth_unstable_by(middle, |a, b| {
+        component(&a.point, axis)
+            .partial_cmp(&component(&b.point, axis))
+            .unwrap_or(Ordering::Equal)
+    });
+    axes[middle] = axis;
+
+    let (left, right) = photons.split_at_mut(middle);
+    let (left_axes, right_axes) = axes.split_at_mut(middle);
+    build(left, left_axes);
+    build(&mut right[1..], &mut right_axes[1..]);
+}
+
+// A photon arriving from the environment, from a direction chosen by the environment's light
+// sampling. It starts on a disk facing that direction just outside the sphere around the
+// scene, so that it may land anywhere in the scene. Returns the photon's ray and its power
+// (radiance times area over the density of its direction).
+fn environment_photon(
+    environment: &dyn Environment,
+    center: &Vec3,
+    radius: f32,
+) -> Option<(Ray, Vec3)> {
+    let sample = environment.sample()?;
+    if sample.pdf <= 0.0 {
+        return None;
+    }
+    let (u, v) = orthonormal_basis(&sample.direction);
+    let (x, y) = random_in_unit_disk();
+    let origin = *center + radius * sample.direction + radius * (x * u + y * v);
+    let area = f32::consts::PI * radius * radius;
+    Some((
+        Ray::new(origin, -1.0 * sample.direction),
+        (area / sample.pdf) * sample.radiance,
+    ))
+}
+
+// Follows a photon from a light or the environment, leaving a copy on every diffuse surface it
+// hits. path is how the photon's light got to where it starts.
+fn trace_photon(
+    scene: &Scene,
+    ray: Ray,
+    power: Vec3,
+    path: PhotonPath,
+    max_depth: u8,
+    photons: &mut Vec<Photon>,
+) -> Result<()> {
+    let mut ray = ray;
+    let mut power = power;
+    let mut path = path;
+    for _ in 0..max_depth {
+        count_ray(RayKind::Secondary);
+        let hit_record = match scene.hit_test(&ray, 0.001, f32::MAX) {
+            Some(hit_record) => hit_record,
+            None => return Ok(()),
+        };
+
+        if is_diffuse(&hit_record) {
+            let direction = ray.direction().unit_vector()?;
+            photons.push(Photon {
+                point: hit_record.point,
+                direction: -1.0 * direction,
+                power,
+                path,
+            });
+
+            let normal = hit_record.normal;
+            let scattered = random_cosine_direction(&normal);
+            let pdf = dot(&scattered, &normal) / f32::consts::PI;
+            if pdf <= 0.0 {
+                return Ok(());
+            }
+            let bsdf = hit_record
+                .material
+                .bsdf(&hit_record, &scattered)
+                .unwrap_or_else(Vec3::origin);
+            power = power * (bsdf / pdf);
+            path = PhotonPath::Indirect;
+            ray = Ray::new(hit_record.point, scattered);
+        } else {
+            match hit_record.material.scatter(&ray, &hit_record)? {
+                Some((scattered, attenuation)) => {
+                    power = power * attenuation;
+                    if path != PhotonPath::Indirect {
+                        path = PhotonPath::Caustic;
+                    }
+                    ray = scattered;
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+    Ok(())
+}
+
+// The light arriving at the camera along ray, found by progressive photon mapping: the ray is
+// followed through mirrors and glass to a diffuse surface, which is lit by shadow rays to the
+// lights, by the caustic photons near it, and by --final_gather rays that look up the photons
+// wherever they land. With no final gathering, all of the light from the environment and
+// all of the indirect light come from the photons near the surface, which is faster but
+// blotchier.
+//
+// Directional lights send out no photons, so their light is only followed for a single diffuse
+// bounce.
+pub fn photon_mapping(
+    ray: &Ray,
+    config: &Config,
+    scene: &Scene,
+    environment: &dyn Environment,
+    photon_map: &PhotonMap,
+) -> Result<Vec3> {
+    count_ray(RayKind::Primary);
+    let (hit_record, throughput) = match find_diffuse(ray, config, scene, environment)? {
+        Found::Diffuse(hit_record, throughput) => (hit_record, throughput),
+        Found::Light(radiance) | Found::Specular(radiance) => return Ok(radiance),
+    };
+
+    let mut radiance = Vec3::origin();
+    for light in &scene.lights {
+        radiance = radiance + light_contribution(scene, &hit_record, light.as_ref())?;
+    }
+
+    if config.final_gather == 0 {
+        radiance = radiance
+            + photon_map.estimate(
+                &hit_record,
+                &[
+                    PhotonPath::Environment,
+                    PhotonPath::Caustic,
+                    PhotonPath::Indirect,
+                ],
+            );
+        return Ok(throughput * radiance);
+    }
+
+    radiance = radiance + photon_map.estimate(&hit_record, &[PhotonPath::Caustic]);
+    let normal = hit_record.normal;
+    let mut gathered = Vec3::origin();
+    for _ in 0..config.final_gather {
+        let direction = random_cosine_direction(&normal);
+        let pdf = dot(&direction, &normal) / f32::consts::PI;
+        if pdf <= 0.0 {
+            continue;
+        }
+        let bsdf = hit_record
+            .material
+            .bsdf(&hit_record, &direction)
+            .unwrap_or_else(Vec3::origin);
+        count_ray(RayKind::Secondary);
+        let gather_ray = Ray::new(hit_record.point, direction);
+        let light = match find_diffuse(&gather_ray, config, scene, environment)? {
+            Found::Diffuse(gather_hit, gather_throughput) => {
+                gather_throughput
+                    * photon_map.estimate(
+                        &gather_hit,
+                        &[
+                            PhotonPath::Direct,
+                            PhotonPath::Environment,
+                            PhotonPath::Caustic,
+                            PhotonPath::Indirect,
+                        ],
+                    )
+            }
+            Found::Light(light) => light,
+            // The caustic photons already carry this light.
+            Found::Specular(_) => Vec3::origin(),
+        };
+        gathered = gathered + (bsdf / pdf) * light;
+    }
+    radiance = radiance + gathered / config.final_gather as f32;
+    Ok(throughput * radiance)
+}
+
+enum Found<'a> {
+    // A diffuse surface, and the attenuation of the mirrors and glass on the way to it.
+    Diffuse(HitRecord<'a>, Vec3),
+    // The ray left the scene straight away (carrying the environment's light), or was
+    // absorbed.
+    Light(Vec3),
+    // The ray left the scene after going through mirrors or glass.
+    Specular(Vec3),
+}
+
+// Follows ray (already counted) through mirrors and glass until it reaches a diffuse surface.
+fn find_diffuse<'a>(
+    ray: &Ray,
+    config: &Config,
+    scene: &'a Scene,
+    environment: &dyn Environment,
+) -> Result<Found<'a>> {
+    let mut throughput = Vec3::cartesian(1.0, 1.0, 1.0);
+    let mut hit_record = match scene.hit_test(ray, 0.001, f32::MAX) {
+        Some(hit_record) => hit_record,
+        None => {
+            count_path(0);
+            return Ok(Found::Light(
+                environment.radiance(&ray.direction().unit_vector()?),
+            ));
+        }
+    };
+    // None while still on the camera ray.
+    let mut current: Option<Ray> = None;
+    let mut depth = 0;
+    loop {
+        if depth >= usize::from(config.max_depth) {
+            count_max_depth_termination();
+            count_path(depth + 1);
+            return Ok(Found::Light(Vec3::origin()));
+        }
+        if is_diffuse(&hit_record) {
+            count_path(depth + 1);
+            return Ok(Found::Diffuse(hit_record, throughput));
+        }
+        let incoming = current.as_ref().unwrap_or(ray);
+        let scattered = match hit_record.material.scatter(incoming, &hit_record)? {
+            Some((scattered, attenuation)) => {
+                throughput = throughput * attenuation;
+                scattered
+            }
+            None => {
+                count_path(depth + 1);
+                return Ok(Found::Light(Vec3::origin()));
+            }
+        };
+        depth += 1;
+        count_ray(RayKind::Secondary);
+        hit_record = match scene.hit_test(&scattered, 0.001, f32::MAX) {
+            Some(hit_record) => hit_record,
+            None => {
+                count_path(depth);
+                return Ok(Found::Specular(
+                    throughput * environment.radiance(&scattered.direction().unit_vector()?),
+                ));
+            }
+        };
+        current = Some(scattered);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use structopt::StructOpt;
+
+    use super::*;
+    use crate::color::Color;
+    use crate::environment::Gradient;
+    use crate::integrator::radiance;
+    use crate::light::PointLight;
+    use crate::material::Lambertian;
+    use crate::sphere::Sphere;
+    use crate::unit_random::seed_unit_random;
+
+    #[test]
+    fn test_matches_path_tracing() {
+        // A diffuse ball on a larger diffuse ball, lit by a point light and the sky. The scene
+        // is small, so that plenty of the environment's photons land on it.
+        let white = || Lambertian::new(Color::new(0.8, 0.8, 0.8).unwrap());
+        let mut scene = Scene::from_world(vec![
+            Sphere::new(&Vec3::cartesian(0.0, -3.5, -2.0), 3.0, white()).unwrap(),
+            Sphere::new(&Vec3::cartesian(0.0, 0.0, -2.0), 0.5, white()).unwrap(),
+        ]);
+        scene.lights.push(Box::new(PointLight::new(
+            Vec3::cartesian(1.0, 1.5, -1.0),
+            Vec3::cartesian(2.0, 2.0, 2.0),
+        )));
+        let environment = Gradient::new(200.0).unwrap();
+        let rays = (0..16)
+            .map(|i| {
+                let (x, y) = ((i % 4) as f32 * 0.2 - 0.3, (i / 4) as f32 * 0.2 - 0.5);
+                Ray::new(Vec3::origin(), Vec3::cartesian(x, y, -1.0))
+            })
+            .collect::<Vec<_>>();
+
+        let config = Config::from_iter(&["myray"]);
+        let mut path = Vec3::origin();
+        for (i, ray) in rays.iter().enumerate() {
+            for sample in 0..1000 {
+                seed_unit_random((i * 1000 + sample) as u64);
+                path = path + radiance(ray, &config, &scene, &environment).unwrap();
+            }
+        }
+        let path = path / (rays.len() * 1000) as f32;
+
+        // Without a final gather, all but the point light's direct light, including the light
+        // from the sky, is estimated from the photons. With it, they are looked up where the
+        // gather rays land instead.
+        for gather in &["0", "8"] {
+            let args = [
+                "myray",
+                "--photons",
+                "20000",
+                "--photon_radius",
+                "0.2",
+                "--final_gather",
+                gather,
+            ];
+            let config = Config::from_iter(&args);
+            let passes = 16;
+            let mut ppm = Vec3::origin();
+            for pass in 0..passes {
+                seed_unit_random(u64::from(pass) + 1_000_000);
+                let map = PhotonMap::trace(&config, &scene, &environment, pass).unwrap();
+                for ray in &rays {
+                    ppm = ppm + photon_mapping(ray, &config, &scene, &environment, &map).unwrap();
+                }
+            }
+            let ppm = ppm / (rays.len() as u32 * passes) as f32;
+            assert!((path - ppm).length() < 0.05 * path.length(), "{}", gather);
+        }
+    }
+
+    #[test]
+    fn test_photon_map() {
+        seed_unit_random(3);
+        let mut photons = (0..500)
+            .map(|_| Photon {
+                point: Vec3::cartesian(unit_random(), unit_random(), 0.1 * unit_random()),
+                direction: Vec3::cartesian(0.0, 0.0, 1.0),
+                power: Vec3::cartesian(1.0, 1.0, 1.0),
+                path: PhotonPath::Direct,
+            })
+            .collect::<Vec<_>>();
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        let map = PhotonMap {
+            photons: photons.clone(),
+            axes,
+            radius: 0.2,
+        };
+
+        // The tree finds exactly the photons that a search through all of them does.
+        for _ in 0..20 {
+            let point = Vec3::cartesian(unit_random(), unit_random(), 0.0);
+            let mut found = 0;
+            map.for_each_near(&point, &mut |_| found += 1);
+            let expected = photons
+                .iter()
+                .filter(|photon| (photon.point - point).squared_length() <= 0.04)
+                .count();
+            assert_eq!(expected, found);
+        }
+
+        assert_eq!(0.5, photon_radius(0.5, 0));
+        assert!(photon_radius(0.5, 10) < photon_radius(0.5, 1));
+    }
+}
@@ -0,0 +1,189 @@
+use std::f32;
+
+use crate::config::Config;
+use crate::environment::Environment;
+use crate::errors::*;
+use crate::hittest::HitTest;
+use crate::integrator::{is_diffuse, light_contribution};
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::stats::{count_max_depth_termination, count_path, count_ray, RayKind};
+use crate::util::random_cosine_direction;
+use crate::vec3::Vec3;
+
+// Glass splits every ray that hits it in two, so the number of rays grows exponentially with
+// depth. Whitted tracing stops at this depth (or --max_depth, if smaller) and drops branches
+// that carry less than MIN_WEIGHT of the light seen by the camera.
+const WHITTED_MAX_DEPTH: u8 = 8;
+const MIN_WEIGHT: f32 = 1.0e-3;
+
+// How much of the sky is visible from the surface seen along ray: --ao_samples rays leave the
+// surface, spread like light arriving at a diffuse surface, and any that hit something within
+// --ao_distance count as blocked. Rays that miss the scene see the environment.
+pub fn ambient_occlusion(
+    ray: &Ray,
+    config: &Config,
+    scene: &Scene,
+    environment: &dyn Environment,
+) -> Result<Vec3> {
+    count_ray(RayKind::Primary);
+    let hit_record = match scene.hit_test(ray, 0.001, f32::MAX) {
+        Some(hit_record) => hit_record,
+        None => {
+            count_path(0);
+            return Ok(environment.radiance(&ray.direction().unit_vector()?));
+        }
+    };
+    count_path(1);
+
+    let mut open = 0;
+    for _ in 0..config.ao_samples {
+        count_ray(RayKind::Shadow);
+        let direction = random_cosine_direction(&hit_record.normal);
+        let occlusion_ray = Ray::new(hit_record.point, direction);
+        if scene
+            .hit_test(&occlusion_ray, 0.001, config.ao_distance)
+            .is_none()
+        {
+            open += 1;
+        }
+    }
+    let visible = open as f32 / u32::max(config.ao_samples, 1) as f32;
+    Ok(Vec3::cartesian(visible, visible, visible))
+}
+
+// The light arriving along ray, found by Whitted-style ray tracing: diffuse surfaces are lit
+// only by shadow rays to the lights, plus the environment in the direction they face, and
+// mirrors and glass send rays in every direction they reflect or refract light (weighted by
+// how much goes each way) instead of picking one at random. Nothing is random, so a single
+// pass gives a finished (if aliased) image.
+pub fn whitted(
+    ray: &Ray,
+    config: &Config,
+    scene: &Scene,
+    environment: &dyn Environment,
+) -> Result<Vec3> {
+    count_ray(RayKind::Primary);
+    let ones = Vec3::cartesian(1.0, 1.0, 1.0);
+    whitted_ray(ray, config, scene, environment, ones, 0)
+}
+
+fn whitted_ray(
+    ray: &Ray,
+    config: &Config,
+    scene: &Scene,
+    environment: &dyn Environment,
+    weight: Vec3,
+    depth: u8,
+) -> Result<Vec3> {
+    let hit_record = match scene.hit_test(ray, 0.001, f32::MAX) {
+        Some(hit_record) => hit_record,
+        None => {
+            count_path(usize::from(depth));
+            return Ok(environment.radiance(&ray.direction().unit_vector()?));
+        }
+    };
+    if depth >= u8::min(config.max_depth, WHITTED_MAX_DEPTH) {
+        count_max_depth_termination();
+        count_path(usize::from(depth) + 1);
+        return Ok(Vec3::origin());
+    }
+
+    if is_diffuse(&hit_record) {
+        count_path(usize::from(depth) + 1);
+        let mut radiance = hit_record.material.albedo() * environment.radiance(&hit_record.normal);
+        for light in &scene.lights {
+            radiance = radiance + light_contribution(scene, &hit_record, light.as_ref())?;
+        }
+        return Ok(radiance);
+    }
+
+    let mut radiance = Vec3::origin();
+    for (scattered, attenuation) in hit_record.material.specular(ray, &hit_record)? {
+        let branch_weight = weight * attenuation;
+        let max = f32::max(
+            branch_weight.x(),
+            f32::max(branch_weight.y(), branch_weight.z()),
+        );
+        if max < MIN_WEIGHT {
+            continue;
+        }
+        count_ray(RayKind::Secondary);
+        radiance = radiance
+            + attenuation
+                * whitted_ray(
+                    &scattered,
+                    config,
+                    scene,
+                    environment,
+                    branch_weight,
+                    depth + 1,
+                )?;
+    }
+    Ok(radiance)
+}
+
+#[cfg(test)]
+mod test {
+    use structopt::StructOpt;
+
+    use super::*;
+    use crate::color::Color;
+    use crate::environment::Gradient;
+    use crate::material::{Dielectric, Lambertian};
+    use crate::sphere::Sphere;
+    use crate::stats::rays_traced;
+    use crate::unit_random::seed_unit_random;
+
+    #[test]
+    fn test_preview() {
+        let floor = Lambertian::new(Color::new(0.5, 0.5, 0.5).unwrap());
+        let scene = Scene::from_world(vec![
+            Sphere::new(&Vec3::cartesian(0.0, -100.5, -1.0), 100.0, floor).unwrap(),
+            Sphere::new(&Vec3::cartesian(0.0, 0.0, -1.0), 0.5, Dielectric::new(1.5)).unwrap(),
+        ]);
+        let config = Config::from_iter(&["myray", "--ao_samples", "64"]);
+        let environment = Gradient::new(200.0).unwrap();
+
+        // The floor far from the ball sees the whole sky; right beside it, it does not.
+        let open = Ray::new(Vec3::origin(), Vec3::cartesian(3.0, -0.5, -1.0));
+        let beside = Ray::new(Vec3::origin(), Vec3::cartesian(0.0, -0.5, -0.45));
+        let ao = |ray| ambient_occlusion(ray, &config, &scene, &environment).unwrap();
+        assert_eq!(1.0, ao(&open).x());
+        assert!(ao(&beside).x() < 0.9);
+
+        // Whitted tracing through the glass ball does not depend on the random sequence.
+        let through = Ray::new(Vec3::origin(), Vec3::cartesian(0.05, 0.05, -1.0));
+        seed_unit_random(1);
+        let first = whitted(&through, &config, &scene, &environment).unwrap();
+        seed_unit_random(2);
+        assert_eq!(
+            first,
+            whitted(&through, &config, &scene, &environment).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_whitted_ray_count() {
+        let mut world = Vec::new();
+        for i in 0..3 {
+            let center = Vec3::cartesian(i as f32 - 1.0, 0.0, -2.0);
+            world.push(Sphere::new(&center, 0.5, Dielectric::new(1.5)).unwrap());
+        }
+        let scene = Scene::from_world(world);
+        let config = Config::from_iter(&["myray"]);
+        let environment = Gradient::new(200.0).unwrap();
+
+        // Rays bouncing between the balls split at every hit; the depth limit and the dropped
+        // dim branches keep each camera ray well short of 2^(WHITTED_MAX_DEPTH + 1) rays.
+        for i in 0..20 {
+            let ray = Ray::new(
+                Vec3::origin(),
+                Vec3::cartesian(i as f32 * 0.05 - 0.5, 0.1, -1.0),
+            );
+            let before = rays_traced();
+            whitted(&ray, &config, &scene, &environment).unwrap();
+            assert!(rays_traced() - before < 1 << (WHITTED_MAX_DEPTH + 1));
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use crate::bdpt::{bidirectional, Splat};
+use crate::camera::Camera;
+use crate::config::Config;
+use crate::debug::{debug_color, RenderMode};
+use crate::environment::Environment;
+use crate::errors::*;
+use crate::fb::IncrementalFrameBuffer;
+use crate::integrator::{aov_channel_count, radiance, sample_aovs, Integrator};
+use crate::photon::{photon_mapping, PhotonMap};
+use crate::preview::{ambient_occlusion, whitted};
+use crate::scene::Scene;
+use crate::spectral::spectral_radiance;
+use crate::unit_random::{seed_unit_random, unit_random};
+use crate::vec3::Vec3;
+
+// Each animation frame gets different sequences, so that the noise does not stay in place on
+// the screen while the scene moves.
+pub fn sample_seed(frame: Option<u32>, pass: u32, x: usize, y: usize) -> u64 {
+    let seed = (u64::from(pass) << 40) ^ ((y as u64) << 20) ^ x as u64;
+    match frame {
+        Some(frame) => {
+            seed ^ u64::from(frame)
+                .wrapping_add(1)
+                .wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        }
+        None => seed,
+    }
+}
+
+// Work done once for a whole pass gets a sequence that no sample uses.
+pub fn pass_seed(frame: Option<u32>, pass: u32) -> u64 {
+    !sample_seed(frame, pass, 0, 0)
+}
+
+// The render passes are needed for --aov_output, and for their albedo, normal, and depth
+// when denoising.
+pub fn wants_aovs(config: &Config) -> bool {
+    config.aov_output.is_some() || config.denoise.is_some()
+}
+
+// Takes the samples of the image for one camera and frame, with the integrator and mode chosen
+// in config.
+pub struct Sampler<'a> {
+    config: &'a Config,
+    scene: &'a Scene,
+    camera: &'a dyn Camera,
+    environment: &'a dyn Environment,
+    frame: Option<u32>,
+
+    // Light paths found by the bidirectional integrator, reused between samples.
+    splats: Vec<Splat>,
+}
+
+impl<'a> Sampler<'a> {
+    pub fn new(
+        config: &'a Config,
+        scene: &'a Scene,
+        camera: &'a dyn Camera,
+        environment: &'a dyn Environment,
+        frame: Option<u32>,
+    ) -> Sampler<'a> {
+        Sampler {
+            config,
+            scene,
+            camera,
+            environment,
+            frame,
+            splats: vec![],
+        }
+    }
+
+    // Takes one sample of pixel (x, y) in pass and splats it into ifb, along with any light the
+    // sample's light path carried to other pixels. Every sample gets its own random sequence,
+    // so a pixel comes out the same whether or not the rest of the image is rendered.
+    pub fn sample_pixel(
+        &mut self,
+        ifb: &mut IncrementalFrameBuffer,
+        photon_map: Option<&PhotonMap>,
+        pass: u32,
+        x: usize,
+        y: usize,
+    ) -> Result<()> {
+        seed_unit_random(sample_seed(self.frame, pass, x, y));
+        let px = x as f32 + unit_random();
+        let py = y as f32 + unit_random();
+        let (radiance, values) = self.sample_color(px, py, photon_map)?;
+        ifb.splat_channels(px, py, &radiance, &values);
+        if self.config.integrator == Integrator::Bidirectional {
+            let width = self.config.screen_width as f32;
+            let height = self.config.screen_height as f32;
+            ifb.add_light_paths(1);
+            for splat in self.splats.drain(..) {
+                ifb.add_light(splat.u * width, splat.v * height, &splat.radiance);
+            }
+        }
+        Ok(())
+    }
+
+    // The light arriving through image position (px, py), and the render passes if they are
+    // wanted.
+    fn sample_color(
+        &mut self,
+        px: f32,
+        py: f32,
+        photon_map: Option<&PhotonMap>,
+    ) -> Result<(Vec3, Vec<f32>)> {
+        let (config, scene, environment) = (self.config, self.scene, self.environment);
+        let u = px / config.screen_width as f32;
+        let v = py / config.screen_height as f32;
+        let aovs = wants_aovs(config);
+        match self.camera.get_ray(u, v) {
+            Some(ray) if aovs => sample_aovs(&ray, config, scene, environment),
+            Some(ray) if config.integrator == Integrator::Bidirectional => Ok((
+                bidirectional(
+                    &ray,
+                    self.camera,
+                    config,
+                    scene,
+                    environment,
+                    &mut self.splats,
+                )?,
+                vec![],
+            )),
+            Some(ray) if config.integrator == Integrator::PhotonMapping => match photon_map {
+                Some(photon_map) => Ok((
+                    photon_mapping(&ray, config, scene, environment, photon_map)?,
+                    vec![],
+                )),
+                None => Ok((Vec3::origin(), vec![])),
+            },
+            Some(ray) if config.integrator == Integrator::AmbientOcclusion => {
+                Ok((ambient_occlusion(&ray, config, scene, environment)?, vec![]))
+            }
+            Some(ray) if config.integrator == Integrator::Whitted => {
+                Ok((whitted(&ray, config, scene, environment)?, vec![]))
+            }
+            Some(ray) if config.spectral && config.integrator == Integrator::Path => {
+                Ok((spectral_radiance(&ray, config, scene, environment)?, vec![]))
+            }
+            Some(ray) if config.mode == RenderMode::Beauty => {
+                Ok((radiance(&ray, config, scene, environment)?, vec![]))
+            }
+            Some(ray) => Ok((
+                debug_color(
+                    config.mode,
+                    &ray,
+                    scene,
+                    config.max_depth,
+                    config.max_distance,
+                )?,
+                vec![],
+            )),
+            None if aovs => Ok((Vec3::origin(), vec![0.0; aov_channel_count(scene)])),
+            None => Ok((Vec3::origin(), vec![])),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use structopt::StructOpt;
+
+    use super::*;
+    use crate::camera::Perspective;
+    use crate::environment::Gradient;
+    use crate::fb::Rect;
+    use crate::filter::{Filter, FilterKind};
+    use crate::world::{load_world, Worlds};
+
+    // Two passes over the pixels in window, as path_trace_inc renders them.
+    fn render(window: &Rect) -> Vec<Vec3> {
+        let config = Config::from_iter(&["myray", "--sw", "16", "--sh", "12"]);
+        let scene = Scene::from_world(load_world(Worlds::ThreeBalls).unwrap());
+        let camera = Perspective::new_with_vert_fov(90.0, 16.0 / 12.0).unwrap();
+        let environment = Gradient::new(200.0).unwrap();
+        let filter = Filter::with_default_radius(FilterKind::Mitchell);
+        let mut ifb = IncrementalFrameBuffer::with_filter(16, 12, filter).unwrap();
+
+        let mut sampler = Sampler::new(&config, &scene, &camera, &environment, None);
+        for pass in 0..2 {
+            for y in window.y0..window.y1 {
+                for x in window.x0..window.x1 {
+                    sampler.sample_pixel(&mut ifb, None, pass, x, y).unwrap();
+                }
+            }
+            ifb.end_pass();
+        }
+        ifb.image()
+    }
+
+    #[test]
+    fn test_crop_matches_full_render() {
+        let full = render(&Rect::full(16, 12));
+        let crop = Rect {
+            x0: 5,
+            y0: 3,
+            x1: 11,
+            y1: 8,
+        };
+        // Pixels just outside the crop window still splat samples into it.
+        let margin = f32::ceil(FilterKind::Mitchell.default_radius()) as usize;
+        let cropped = render(&crop.expand(margin, 16, 12));
+
+        // The images run from the top row down.
+        let index = |x: usize, y: usize| (11 - y) * 16 + x;
+        for y in crop.y0..crop.y1 {
+            for x in crop.x0..crop.x1 {
+                assert_eq!(full[index(x, y)], cropped[index(x, y)], "({}, {})", x, y);
+            }
+        }
+        // Without the margin, the edges of the window would be missing samples.
+        let bare = render(&crop);
+        let corner = index(crop.x0, crop.y0);
+        assert_ne!(full[corner], bare[corner]);
+    }
+}
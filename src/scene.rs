@@ -0,0 +1,100 @@
+use std::fs::File;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::animation::CameraAnimation;
+use crate::camera::CameraSettings;
+use crate::errors::*;
+use crate::hittest::{HitRecord, HitTest};
+use crate::light::Light;
+use crate::ray::Ray;
+use crate::stats::count_intersection_tests;
+use crate::vec3::Vec3;
+use crate::world::World;
+
+// Everything that can be described in a scene file.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Scene {
+    #[serde(default)]
+    pub objects: World,
+
+    #[serde(default)]
+    pub lights: Vec<Box<dyn Light>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera: Option<CameraSettings>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera_animation: Option<CameraAnimation>,
+}
+
+impl Scene {
+    pub fn from_world(objects: World) -> Scene {
+        Scene {
+            objects,
+            lights: vec![],
+            camera: None,
+            camera_animation: None,
+        }
+    }
+
+    // Reads a YAML scene file. Older files containing only a list of objects are also accepted.
+    pub fn open(path: &Path) -> Result<Scene> {
+        let file = File::open(path)?;
+        let value = serde_yaml::from_reader::<_, serde_yaml::Value>(file)?;
+        if value.is_sequence() {
+            Ok(Scene::from_world(serde_yaml::from_value(value)?))
+        } else {
+            Ok(serde_yaml::from_value(value)?)
+        }
+    }
+
+    // Combines the objects and lights of both scenes. A camera (or camera animation) in other
+    // replaces this one.
+    pub fn merge(mut self, other: Scene) -> Scene {
+        self.objects.extend(other.objects);
+        self.lights.extend(other.lights);
+        if other.camera.is_some() {
+            self.camera = other.camera;
+        }
+        if other.camera_animation.is_some() {
+            self.camera_animation = other.camera_animation;
+        }
+        self
+    }
+
+    // The center and radius of a sphere around every object, or None if there are none.
+    pub fn bounding_sphere(&self) -> Option<(Vec3, f32)> {
+        let first = self.objects.first()?;
+        let mut low = first.center();
+        let mut high = first.center();
+        for object in &self.objects {
+            let (c, r) = (object.center(), object.radius());
+            low = Vec3::cartesian(
+                low.x().min(c.x() - r),
+                low.y().min(c.y() - r),
+                low.z().min(c.z() - r),
+            );
+            high = Vec3::cartesian(
+                high.x().max(c.x() + r),
+                high.y().max(c.y() + r),
+                high.z().max(c.z() + r),
+            );
+        }
+        let center = 0.5 * (low + high);
+        let radius = self
+            .objects
+            .iter()
+            .map(|object| (object.center() - center).length() + object.radius())
+            .fold(0.0, f32::max);
+        Some((center, radius))
+    }
+}
+
+impl HitTest for Scene {
+    fn hit_test(&self, ray: &Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'_>> {
+        count_intersection_tests(self.objects.len());
+        self.objects.hit_test(ray, t_min, t_max)
+    }
+}
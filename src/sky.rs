@@ -0,0 +1,271 @@
+use std::f32;
+
+use crate::environment::{Environment, EnvironmentSample};
+use crate::errors::*;
+use crate::unit_random::unit_random;
+use crate::util::range_check;
+use crate::vec3::{dot, orthonormal_basis, Vec3};
+
+// Scales the model's luminance (in kcd/m^2) to roughly the brightness of the gradient
+// background.
+const SKY_SCALE: f32 = 0.05;
+
+// Irradiance from the sun, before the atmosphere, on a surface facing it.
+const SUN_IRRADIANCE: f32 = 10.0;
+
+// Angular radius of the sun's disk, in radians.
+const SUN_RADIUS: f32 = 0.004_654;
+
+// Probability of choosing the sun (rather than the sky) when sampling the environment.
+const SUN_SAMPLE_PROBABILITY: f32 = 0.5;
+
+// Coefficients of the Perez sky luminance distribution function.
+struct Perez {
+    a: f32,
+    b: f32,
+    c: f32,
+    d: f32,
+    e: f32,
+}
+
+impl Perez {
+    // theta: zenith angle of the view direction, gamma: angle between view and sun.
+    fn f(&self, cos_theta: f32, gamma: f32) -> f32 {
+        let cos_gamma = f32::cos(gamma);
+        (1.0 + self.a * f32::exp(self.b / cos_theta))
+            * (1.0 + self.c * f32::exp(self.d * gamma) + self.e * cos_gamma * cos_gamma)
+    }
+}
+
+// The analytic daylight model from Preetham, Shirley, & Smits, "A Practical Analytic Model for
+// Daylight" (1999), with a sun disk that can be sampled as a light.
+pub struct PreethamSky {
+    sun_direction: Vec3,
+    perez_y: Perez,
+    perez_x: Perez,
+    perez_yy: Perez, // chromaticity y, as opposed to luminance Y
+    // Zenith values divided by the Perez function at the zenith.
+    zenith_y: f32,
+    zenith_x: f32,
+    zenith_yy: f32,
+    sun_radiance: Vec3,
+    // 1 - cos(SUN_RADIUS), kept separately since it is tiny compared to the cosine itself.
+    sun_one_minus_cos_max: f32,
+    intensity: f32,
+}
+
+impl PreethamSky {
+    // elevation and azimuth are in degrees. Azimuth 0 is the -z direction (straight ahead of the
+    // default camera), increasing towards +x. turbidity must be in [2, 10].
+    pub fn new(elevation: f32, azimuth: f32, turbidity: f32, intensity: f32) -> Result<Self> {
+        range_check(elevation, -90.0, 90.0)?;
+        range_check(turbidity, 2.0, 10.0)?;
+
+        let t = turbidity;
+        let elevation_rad = elevation.to_radians();
+        let azimuth_rad = azimuth.to_radians();
+        let sun_direction = Vec3::cartesian(
+            f32::cos(elevation_rad) * f32::sin(azimuth_rad),
+            f32::sin(elevation_rad),
+            -f32::cos(elevation_rad) * f32::cos(azimuth_rad),
+        );
+
+        // The model is only defined for the sun above the horizon.
+        let theta_s = f32::min(
+            f32::consts::FRAC_PI_2 - elevation_rad,
+            f32::consts::FRAC_PI_2,
+        );
+
+        let perez_y = Perez {
+            a: 0.1787 * t - 1.4630,
+            b: -0.3554 * t + 0.4275,
+            c: -0.0227 * t + 5.3251,
+            d: 0.1206 * t - 2.5771,
+            e: -0.0670 * t + 0.3703,
+        };
+        let perez_x = Perez {
+            a: -0.0193 * t - 0.2592,
+            b: -0.0665 * t + 0.0008,
+            c: -0.0004 * t + 0.2125,
+            d: -0.0641 * t - 0.8989,
+            e: -0.0033 * t + 0.0452,
+        };
+        let perez_yy = Perez {
+            a: -0.0167 * t - 0.2608,
+            b: -0.0950 * t + 0.0092,
+            c: -0.0079 * t + 0.2102,
+            d: -0.0441 * t - 1.6537,
+            e: -0.0109 * t + 0.0529,
+        };
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (f32::consts::PI - 2.0 * theta_s);
+        let zenith_y = (4.0453 * t - 4.9710) * f32::tan(chi) - 0.2155 * t + 2.4192;
+
+        let t2 = t * t;
+        let ts = theta_s;
+        let ts2 = ts * ts;
+        let ts3 = ts2 * ts;
+        let zenith_x = (0.00166 * ts3 - 0.00375 * ts2 + 0.00209 * ts) * t2
+            + (-0.02903 * ts3 + 0.06377 * ts2 - 0.03202 * ts + 0.00394) * t
+            + (0.11693 * ts3 - 0.21196 * ts2 + 0.06052 * ts + 0.25886);
+        let zenith_yy = (0.00275 * ts3 - 0.00610 * ts2 + 0.00317 * ts) * t2
+            + (-0.04214 * ts3 + 0.08970 * ts2 - 0.04153 * ts + 0.00516) * t
+            + (0.15346 * ts3 - 0.26756 * ts2 + 0.06670 * ts + 0.26688);
+
+        let one_minus_cos_max = 2.0 * f32::powi(f32::sin(SUN_RADIUS / 2.0), 2);
+        let solid_angle = 2.0 * f32::consts::PI * one_minus_cos_max;
+        let sun_radiance = SUN_IRRADIANCE / solid_angle * sun_transmittance(theta_s, turbidity);
+
+        Ok(PreethamSky {
+            sun_direction,
+            zenith_y: zenith_y / perez_y.f(1.0, theta_s),
+            zenith_x: zenith_x / perez_x.f(1.0, theta_s),
+            zenith_yy: zenith_yy / perez_yy.f(1.0, theta_s),
+            perez_y,
+            perez_x,
+            perez_yy,
+            sun_radiance,
+            sun_one_minus_cos_max: one_minus_cos_max,
+            intensity,
+        })
+    }
+
+    pub fn sun_direction(&self) -> &Vec3 {
+        &self.sun_direction
+    }
+
+    fn sky_radiance(&self, direction: &Vec3) -> Vec3 {
+        if direction.y() <= 0.0 {
+            return Vec3::origin();
+        }
+
+        // Keep the Perez function finite right at the horizon.
+        let cos_theta = f32::max(direction.y(), 0.01);
+        let gamma = f32::acos(dot(direction, &self.sun_direction).clamp(-1.0, 1.0));
+
+        let lum = SKY_SCALE * self.zenith_y * self.perez_y.f(cos_theta, gamma);
+        let x = self.zenith_x * self.perez_x.f(cos_theta, gamma);
+        let y = self.zenith_yy * self.perez_yy.f(cos_theta, gamma);
+        xyy_to_rgb(x, y, lum)
+    }
+
+    fn in_sun(&self, direction: &Vec3) -> bool {
+        self.sun_direction.y() > -SUN_RADIUS && self.in_sun_cone(direction)
+    }
+
+    fn in_sun_cone(&self, direction: &Vec3) -> bool {
+        1.0 - dot(direction, &self.sun_direction) <= self.sun_one_minus_cos_max
+    }
+
+    fn sun_pdf(&self) -> f32 {
+        1.0 / (2.0 * f32::consts::PI * self.sun_one_minus_cos_max)
+    }
+}
+
+impl Environment for PreethamSky {
+    fn radiance(&self, direction: &Vec3) -> Vec3 {
+        let sun = if self.in_sun(direction) {
+            self.sun_radiance
+        } else {
+            Vec3::origin()
+        };
+        self.intensity * (self.sky_radiance(direction) + sun)
+    }
+
+    // Samples the sun disk and the upper hemisphere (uniformly) with equal probability.
+    fn sample(&self) -> Option<EnvironmentSample> {
+        let direction = if unit_random() < SUN_SAMPLE_PROBABILITY {
+            let cos_theta = 1.0 - unit_random() * self.sun_one_minus_cos_max;
+            let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta * cos_theta));
+            let phi = 2.0 * f32::consts::PI * unit_random();
+            let (u, v) = orthonormal_basis(&self.sun_direction);
+            sin_theta * f32::cos(phi) * u
+                + sin_theta * f32::sin(phi) * v
+                + cos_theta * self.sun_direction
+        } else {
+            let y = unit_random();
+            let r = f32::sqrt(f32::max(0.0, 1.0 - y * y));
+            let phi = 2.0 * f32::consts::PI * unit_random();
+            Vec3::cartesian(r * f32::cos(phi), y, r * f32::sin(phi))
+        };
+
+        let pdf = self.pdf(&direction);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some(EnvironmentSample {
+            direction,
+            radiance: self.radiance(&direction),
+            pdf,
+        })
+    }
+
+    fn pdf(&self, direction: &Vec3) -> f32 {
+        let sun = if self.in_sun_cone(direction) {
+            self.sun_pdf()
+        } else {
+            0.0
+        };
+        let sky = if direction.y() > 0.0 {
+            1.0 / (2.0 * f32::consts::PI)
+        } else {
+            0.0
+        };
+        SUN_SAMPLE_PROBABILITY * sun + (1.0 - SUN_SAMPLE_PROBABILITY) * sky
+    }
+}
+
+// CIE xyY to linear sRGB.
+fn xyy_to_rgb(x: f32, y: f32, lum: f32) -> Vec3 {
+    if y <= 0.0 {
+        return Vec3::origin();
+    }
+    let cx = x / y * lum;
+    let cz = (1.0 - x - y) / y * lum;
+    Vec3::cartesian(
+        f32::max(3.2406 * cx - 1.5372 * lum - 0.4986 * cz, 0.0),
+        f32::max(-0.9689 * cx + 1.8758 * lum + 0.0415 * cz, 0.0),
+        f32::max(0.0557 * cx - 0.2040 * lum + 1.0570 * cz, 0.0),
+    )
+}
+
+// Attenuation of sunlight by Rayleigh and aerosol scattering, evaluated at representative
+// wavelengths for red, green, and blue (from the appendix of Preetham et al).
+fn sun_transmittance(theta_s: f32, turbidity: f32) -> Vec3 {
+    let theta_deg = theta_s.to_degrees();
+    let mass = 1.0 / (f32::cos(theta_s) + 0.15 * f32::powf(93.885 - theta_deg, -1.253));
+    let beta = 0.04608 * turbidity - 0.04586;
+    let alpha = 1.3;
+
+    let channel = |lambda_um: f32| {
+        let rayleigh = f32::exp(-0.008_735 * f32::powf(lambda_um, -4.08) * mass);
+        let aerosol = f32::exp(-beta * f32::powf(lambda_um, -alpha) * mass);
+        rayleigh * aerosol
+    };
+    Vec3::cartesian(channel(0.68), channel(0.55), channel(0.44))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sky() {
+        let sky = PreethamSky::new(45.0, 30.0, 3.0, 1.0).unwrap();
+
+        let zenith = sky.radiance(&Vec3::cartesian(0.0, 1.0, 0.0));
+        assert!(zenith.z() > zenith.x());
+        assert_eq!(
+            Vec3::origin(),
+            sky.radiance(&Vec3::cartesian(0.0, -1.0, 0.0))
+        );
+
+        let sun = sky.radiance(sky.sun_direction());
+        assert!(sun.x() > 1000.0 * zenith.x());
+
+        for _ in 0..100 {
+            let sample = sky.sample().unwrap();
+            assert_eq!(sample.pdf, sky.pdf(&sample.direction));
+        }
+    }
+}
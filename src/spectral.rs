@@ -0,0 +1,336 @@
+use std::f32;
+
+use crate::config::Config;
+use crate::environment::Environment;
+use crate::errors::*;
+use crate::hittest::HitTest;
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::stats::{count_max_depth_termination, count_path, count_ray, RayKind};
+use crate::unit_random::unit_random;
+use crate::vec3::{cross, dot, Vec3};
+
+// The wavelengths traced, in nanometers: the range the eye can see.
+pub const MIN_WAVELENGTH: f32 = 380.0;
+pub const MAX_WAVELENGTH: f32 = 780.0;
+
+// The wavelengths carried by each path: a hero wavelength chosen at random, and others evenly
+// spaced after it, wrapping around the range.
+pub const WAVELENGTHS: usize = 4;
+
+// The light (or the fraction of it) at each of a path's wavelengths.
+pub type Spectrum = [f32; WAVELENGTHS];
+
+// Hero wavelength sampling (Wilkie et al.): the wavelengths for a path, from a random number.
+// The first is the hero, the one that decides directions at surfaces where wavelengths part.
+pub fn sample_wavelengths(u: f32) -> Spectrum {
+    let mut wavelengths = [0.0; WAVELENGTHS];
+    for (i, wavelength) in wavelengths.iter_mut().enumerate() {
+        let offset = u + i as f32 / WAVELENGTHS as f32;
+        let wrapped = offset - offset.floor();
+        *wavelength = MIN_WAVELENGTH + wrapped * (MAX_WAVELENGTH - MIN_WAVELENGTH);
+    }
+    wavelengths
+}
+
+// The multi-lobe fit of Wyman, Sloan and Shirley to one of the CIE 1931 color matching
+// functions: a Gaussian with different widths either side of its peak.
+fn lobe(wavelength: f32, peak: f32, below: f32, above: f32) -> f32 {
+    let width = if wavelength < peak { below } else { above };
+    let t = (wavelength - peak) / width;
+    f32::exp(-0.5 * t * t)
+}
+
+// The CIE 1931 XYZ color matching functions.
+fn xyz(wavelength: f32) -> Vec3 {
+    let x = 1.056 * lobe(wavelength, 599.8, 37.9, 31.0)
+        + 0.362 * lobe(wavelength, 442.0, 16.0, 26.7)
+        - 0.065 * lobe(wavelength, 501.1, 20.4, 26.2);
+    let y =
+        0.821 * lobe(wavelength, 568.8, 46.9, 40.5) + 0.286 * lobe(wavelength, 530.9, 16.3, 31.1);
+    let z =
+        1.217 * lobe(wavelength, 437.0, 11.8, 36.0) + 0.681 * lobe(wavelength, 459.0, 26.0, 13.8);
+    Vec3::cartesian(x, y, z)
+}
+
+// XYZ to linear sRGB.
+fn xyz_to_rgb(xyz: &Vec3) -> Vec3 {
+    Vec3::cartesian(
+        3.2406 * xyz.x() - 1.5372 * xyz.y() - 0.4986 * xyz.z(),
+        -0.9689 * xyz.x() + 1.8758 * xyz.y() + 0.0415 * xyz.z(),
+        0.0557 * xyz.x() - 0.2040 * xyz.y() + 1.0570 * xyz.z(),
+    )
+}
+
+// Smoothly rises from 0 below start to 1 above end.
+fn smoothstep(start: f32, end: f32, x: f32) -> f32 {
+    let t = ((x - start) / (end - start)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+// Smooth spectra for the red, green and blue parts of a color, adding up to 1 everywhere.
+fn basis(wavelength: f32) -> Vec3 {
+    let blue = 1.0 - smoothstep(480.0, 510.0, wavelength);
+    let red = smoothstep(570.0, 600.0, wavelength);
+    Vec3::cartesian(red, 1.0 - red - blue, blue)
+}
+
+// Converts between spectra and the colors of the film.
+struct Conversion {
+    // Scales each sRGB channel so that an even spectrum of 1 is white (1, 1, 1).
+    white: Vec3,
+
+    // The inverse of the matrix whose columns are the colors of the three basis spectra.
+    upsample: [Vec3; 3],
+}
+
+impl Conversion {
+    // The color of the wavelength, such that the color of a spectrum is the integral of its
+    // value times this.
+    fn weight(&self, wavelength: f32) -> Vec3 {
+        self.white * xyz_to_rgb(&xyz(wavelength))
+    }
+
+    fn new() -> Conversion {
+        let integrate = |f: &dyn Fn(f32) -> Vec3| {
+            let mut sum = Vec3::origin();
+            let mut wavelength = MIN_WAVELENGTH + 0.5;
+            while wavelength < MAX_WAVELENGTH {
+                sum = sum + f(wavelength);
+                wavelength += 1.0;
+            }
+            sum
+        };
+        let flat = integrate(&|wavelength| xyz_to_rgb(&xyz(wavelength)));
+        let mut conversion = Conversion {
+            white: Vec3::cartesian(1.0 / flat.x(), 1.0 / flat.y(), 1.0 / flat.z()),
+            upsample: [Vec3::origin(); 3],
+        };
+        let columns = [
+            integrate(&|wavelength| basis(wavelength).x() * conversion.weight(wavelength)),
+            integrate(&|wavelength| basis(wavelength).y() * conversion.weight(wavelength)),
+            integrate(&|wavelength| basis(wavelength).z() * conversion.weight(wavelength)),
+        ];
+        conversion.upsample = invert(&columns);
+        conversion
+    }
+
+    // A smooth spectrum with the given color, found as a mix of the basis spectra, at one
+    // wavelength. Colors too saturated for the basis would need negative light somewhere,
+    // which is cut off.
+    fn spectrum(&self, color: &Vec3, wavelength: f32) -> f32 {
+        let [r, g, b] = &self.upsample;
+        let mix = Vec3::cartesian(
+            r.x() * color.x() + r.y() * color.y() + r.z() * color.z(),
+            g.x() * color.x() + g.y() * color.y() + g.z() * color.z(),
+            b.x() * color.x() + b.y() * color.y() + b.z() * color.z(),
+        );
+        let basis = basis(wavelength);
+        f32::max(
+            mix.x() * basis.x() + mix.y() * basis.y() + mix.z() * basis.z(),
+            0.0,
+        )
+    }
+}
+
+// The inverse of the matrix with the given columns, as rows.
+fn invert(columns: &[Vec3; 3]) -> [Vec3; 3] {
+    let [a, b, c] = columns;
+    let scale = 1.0 / dot(a, &cross(b, c));
+    [
+        scale * cross(b, c),
+        scale * cross(c, a),
+        scale * cross(a, b),
+    ]
+}
+
+thread_local! {
+    static CONVERSION: Conversion = Conversion::new();
+}
+
+// RGB to spectrum upsampling: the values at the wavelengths of a smooth spectrum with the
+// given color, so that albedos, lights and environments given as RGB can be used for
+// spectral rendering. Converting the spectrum back with to_rgb gives the same color.
+pub fn upsample(color: &Vec3, wavelengths: &Spectrum) -> Spectrum {
+    CONVERSION.with(|conversion| {
+        let mut spectrum = [0.0; WAVELENGTHS];
+        for (value, wavelength) in spectrum.iter_mut().zip(wavelengths.iter()) {
+            *value = conversion.spectrum(color, *wavelength);
+        }
+        spectrum
+    })
+}
+
+// The color of the film for the light found at the wavelengths sampled by sample_wavelengths:
+// through XYZ to linear sRGB, white balanced so that an even spectrum is white.
+pub fn to_rgb(radiance: &Spectrum, wavelengths: &Spectrum) -> Vec3 {
+    CONVERSION.with(|conversion| {
+        let mut color = Vec3::origin();
+        for (value, wavelength) in radiance.iter().zip(wavelengths.iter()) {
+            color = color + *value * conversion.weight(*wavelength);
+        }
+        ((MAX_WAVELENGTH - MIN_WAVELENGTH) / WAVELENGTHS as f32) * color
+    })
+}
+
+fn multiply(a: &Spectrum, b: &Spectrum) -> Spectrum {
+    let mut product = *a;
+    for (value, other) in product.iter_mut().zip(b.iter()) {
+        *value *= other;
+    }
+    product
+}
+
+fn add(a: &Spectrum, b: &Spectrum) -> Spectrum {
+    let mut sum = *a;
+    for (value, other) in sum.iter_mut().zip(b.iter()) {
+        *value += other;
+    }
+    sum
+}
+
+// The light arriving at the camera along ray, found by a path tracer that carries light at
+// several wavelengths instead of as RGB, so that glass with dispersion bends each wavelength
+// by its own amount. Every path is traced at WAVELENGTHS wavelengths at once; once it reaches
+// a surface that would send them different ways, only the hero wavelength goes on.
+//
+// Lights are sampled at every surface as by the path integrator; the environment is only
+// found by rays that escape the scene.
+pub fn spectral_radiance(
+    ray: &Ray,
+    config: &Config,
+    scene: &Scene,
+    environment: &dyn Environment,
+) -> Result<Vec3> {
+    let wavelengths = sample_wavelengths(unit_random());
+    let mut throughput = [1.0; WAVELENGTHS];
+    let mut radiance = [0.0; WAVELENGTHS];
+
+    count_ray(RayKind::Primary);
+    let mut ray = Ray::new(*ray.origin(), *ray.direction());
+    let mut depth = 0;
+    loop {
+        let hit_record = match scene.hit_test(&ray, 0.001, f32::MAX) {
+            Some(hit_record) => hit_record,
+            None => {
+                count_path(usize::from(depth));
+                let escaped = environment.radiance(&ray.direction().unit_vector()?);
+                let escaped = upsample(&escaped, &wavelengths);
+                radiance = add(&radiance, &multiply(&throughput, &escaped));
+                break;
+            }
+        };
+        if depth >= config.max_depth {
+            count_max_depth_termination();
+            count_path(usize::from(depth) + 1);
+            break;
+        }
+
+        for light in &scene.lights {
+            if let Some(sample) = light.sample(&hit_record.point)? {
+                if let Some(bsdf) = hit_record.material.bsdf(&hit_record, &sample.direction) {
+                    count_ray(RayKind::Shadow);
+                    let shadow_ray = Ray::new(hit_record.point, sample.direction);
+                    if scene
+                        .hit_test(&shadow_ray, 0.001, sample.distance)
+                        .is_none()
+                    {
+                        let direct = multiply(
+                            &upsample(&bsdf, &wavelengths),
+                            &upsample(&sample.radiance, &wavelengths),
+                        );
+                        radiance = add(&radiance, &multiply(&throughput, &direct));
+                    }
+                }
+            }
+        }
+
+        // The other wavelengths would leave in other directions, so their paths end here, and
+        // the hero carries the whole estimate.
+        if hit_record.material.is_dispersive() && throughput[1..].iter().any(|t| *t != 0.0) {
+            throughput[0] *= WAVELENGTHS as f32;
+            for value in throughput[1..].iter_mut() {
+                *value = 0.0;
+            }
+        }
+
+        let (scattered, attenuation) =
+            match hit_record
+                .material
+                .scatter_wavelength(&ray, &hit_record, wavelengths[0])?
+            {
+                Some(scattered) => scattered,
+                None => {
+                    count_path(usize::from(depth) + 1);
+                    break;
+                }
+            };
+        throughput = multiply(&throughput, &upsample(&attenuation, &wavelengths));
+        depth += 1;
+        count_ray(RayKind::Secondary);
+        ray = scattered;
+    }
+
+    Ok(to_rgb(&radiance, &wavelengths))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::material::Dispersion;
+
+    // The color of a spectrum, from one sample at each nanometer.
+    fn color_of(spectrum: impl Fn(&Spectrum) -> Spectrum) -> Vec3 {
+        let steps = (MAX_WAVELENGTH - MIN_WAVELENGTH) as usize / WAVELENGTHS;
+        let mut color = Vec3::origin();
+        for step in 0..steps {
+            let wavelengths =
+                sample_wavelengths((step as f32 + 0.5) / steps as f32 / WAVELENGTHS as f32);
+            color = color + to_rgb(&spectrum(&wavelengths), &wavelengths);
+        }
+        color / steps as f32
+    }
+
+    #[test]
+    fn test_spectral() {
+        let close = |a: Vec3, b: Vec3| (a - b).length() < 0.01;
+
+        // White is an even spectrum, and every color survives the trip to a spectrum and back.
+        let white = Vec3::cartesian(1.0, 1.0, 1.0);
+        for value in &upsample(&white, &sample_wavelengths(0.3)) {
+            assert!((value - 1.0).abs() < 0.001);
+        }
+        assert!(close(white, color_of(|_| [1.0; WAVELENGTHS])));
+        for color in &[
+            Vec3::cartesian(0.8, 0.3, 0.3),
+            Vec3::cartesian(0.1, 0.6, 0.2),
+            Vec3::cartesian(0.2, 0.3, 0.7),
+        ] {
+            assert!(close(*color, color_of(|w| upsample(color, w))));
+        }
+
+        // Glass bends blue light more than red.
+        let cauchy = Dispersion::Cauchy {
+            a: 1.5046,
+            b: 0.0042,
+        };
+        let sellmeier = Dispersion::Sellmeier {
+            b: [1.039_612_1, 0.231_792_34, 1.010_469_5],
+            c: [0.006_000_699, 0.020_017_914, 103.560_65],
+        };
+        for dispersion in &[cauchy, sellmeier] {
+            let blue = dispersion.refractive_index(450.0);
+            let red = dispersion.refractive_index(650.0);
+            assert!(blue > red && red > 1.5 && blue < 1.54);
+        }
+
+        // Scene files may not give coefficients that have no index for some visible light.
+        let parse = |dispersion: &str| serde_yaml::from_str::<Dispersion>(dispersion);
+        let sellmeier = "{type: Sellmeier, b: [1.04, 0.23, 1.01], c: [0.006, 0.02, 103.56]}";
+        assert!(parse(sellmeier).is_ok());
+        assert!(parse(&sellmeier.replace("0.02,", "0.3,")).is_err());
+        assert!(parse(&sellmeier.replace("b: [1.04", "b: [-3.0")).is_err());
+        assert!(parse("{type: Cauchy, a: 1.5, b: 0.0042}").is_ok());
+        assert!(parse("{type: Cauchy, a: -1.5, b: 0.0042}").is_err());
+    }
+}
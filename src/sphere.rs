@@ -1,3 +1,5 @@
+use std::f32;
+
 use serde::{Deserialize, Serialize};
 
 use crate::errors::*;
@@ -30,6 +32,22 @@ impl Sphere {
             })
         }
     }
+
+    pub fn center(&self) -> Vec3 {
+        self.center
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+}
+
+// Longitude and latitude of a point on the unit sphere: u goes around the y axis from -x
+// through +z, +x, and -z, and v goes from the bottom pole to the top.
+fn sphere_uv(normal: &Vec3) -> (f32, f32) {
+    let phi = f32::atan2(-normal.z(), normal.x()) + f32::consts::PI;
+    let theta = f32::acos((-normal.y()).clamp(-1.0, 1.0));
+    (phi / (2.0 * f32::consts::PI), theta / f32::consts::PI)
 }
 
 impl HitTest for Sphere {
@@ -50,6 +68,8 @@ impl HitTest for Sphere {
                     point,
                     normal,
                     material: self.material.as_ref(),
+                    uv: sphere_uv(&normal),
+                    object: 0,
                 })
             })
         };
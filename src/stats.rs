@@ -0,0 +1,187 @@
+use std::cell::RefCell;
+use std::str::FromStr;
+
+use crate::errors::*;
+
+// Counters describing the work done by a render. Each thread counts into its own Stats, so
+// counting costs no more than an increment.
+//
+// The scene is a flat list of objects (there is no acceleration structure), so every ray is
+// tested against every object.
+#[derive(Debug, Default, Clone)]
+pub struct Stats {
+    pub primary_rays: u64,
+    pub secondary_rays: u64,
+    pub shadow_rays: u64,
+    pub intersection_tests: u64,
+
+    // Paths stopped by --max_depth rather than by escaping the scene or being absorbed.
+    pub max_depth_terminations: u64,
+
+    // The number of paths that hit each number of surfaces.
+    pub path_lengths: Vec<u64>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RayKind {
+    Primary,
+    Secondary,
+    Shadow,
+}
+
+// How the stats are reported at the end of a render.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum StatsFormat {
+    Table,
+    Json,
+}
+
+impl FromStr for StatsFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<StatsFormat> {
+        match s.to_lowercase().as_str() {
+            "table" => Ok(StatsFormat::Table),
+            "json" => Ok(StatsFormat::Json),
+            _ => Err(ErrorKind::ParseError(
+                s.to_string(),
+                "Must be 'table' or 'json'.".to_string(),
+            )
+            .into()),
+        }
+    }
+}
+
+thread_local! {
+    static STATS: RefCell<Stats> = RefCell::new(Stats::default());
+}
+
+// Counts a ray tested against the scene.
+pub fn count_ray(kind: RayKind) {
+    STATS.with(|stats| {
+        let mut stats = stats.borrow_mut();
+        match kind {
+            RayKind::Primary => stats.primary_rays += 1,
+            RayKind::Secondary => stats.secondary_rays += 1,
+            RayKind::Shadow => stats.shadow_rays += 1,
+        }
+    });
+}
+
+pub fn count_intersection_tests(count: usize) {
+    STATS.with(|stats| stats.borrow_mut().intersection_tests += count as u64);
+}
+
+// Counts a finished path that hit length surfaces.
+pub fn count_path(length: usize) {
+    STATS.with(|stats| {
+        let mut stats = stats.borrow_mut();
+        if stats.path_lengths.len() <= length {
+            stats.path_lengths.resize(length + 1, 0);
+        }
+        stats.path_lengths[length] += 1;
+    });
+}
+
+pub fn count_max_depth_termination() {
+    STATS.with(|stats| stats.borrow_mut().max_depth_terminations += 1);
+}
+
+// The number of rays this thread has tested against the scene.
+pub fn rays_traced() -> u64 {
+    STATS.with(|stats| stats.borrow().rays())
+}
+
+// A copy of the counts made by this thread.
+pub fn thread_stats() -> Stats {
+    STATS.with(|stats| stats.borrow().clone())
+}
+
+impl Stats {
+    pub fn rays(&self) -> u64 {
+        self.primary_rays + self.secondary_rays + self.shadow_rays
+    }
+
+    // Adds the counts from another thread.
+    pub fn merge(&mut self, other: &Stats) {
+        self.primary_rays += other.primary_rays;
+        self.secondary_rays += other.secondary_rays;
+        self.shadow_rays += other.shadow_rays;
+        self.intersection_tests += other.intersection_tests;
+        self.max_depth_terminations += other.max_depth_terminations;
+        if self.path_lengths.len() < other.path_lengths.len() {
+            self.path_lengths.resize(other.path_lengths.len(), 0);
+        }
+        for (count, other_count) in self.path_lengths.iter_mut().zip(&other.path_lengths) {
+            *count += other_count;
+        }
+    }
+
+    pub fn table(&self) -> String {
+        let mut table = String::new();
+        let mut row = |name: &str, value: u64| table += &format!("{:<28}{:>14}\n", name, value);
+        row("Primary rays", self.primary_rays);
+        row("Secondary rays", self.secondary_rays);
+        row("Shadow rays", self.shadow_rays);
+        row("Total rays", self.rays());
+        row("Intersection tests", self.intersection_tests);
+        row("Paths ended by max depth", self.max_depth_terminations);
+
+        let paths = f64::max(self.path_lengths.iter().sum::<u64>() as f64, 1.0);
+        table += "Path length (surfaces hit)\n";
+        for (length, count) in self.path_lengths.iter().enumerate() {
+            table += &format!(
+                "  {:<26}{:>14}{:>7.1}%\n",
+                length,
+                count,
+                100.0 * *count as f64 / paths
+            );
+        }
+        table
+    }
+
+    pub fn json(&self) -> String {
+        format!(
+            "{{\"primary_rays\":{},\"secondary_rays\":{},\"shadow_rays\":{},\
+             \"intersection_tests\":{},\"max_depth_terminations\":{},\"path_lengths\":[{}]}}",
+            self.primary_rays,
+            self.secondary_rays,
+            self.shadow_rays,
+            self.intersection_tests,
+            self.max_depth_terminations,
+            self.path_lengths
+                .iter()
+                .map(u64::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let mut a = Stats {
+            primary_rays: 2,
+            path_lengths: vec![1, 1],
+            ..Stats::default()
+        };
+        let b = Stats {
+            shadow_rays: 3,
+            path_lengths: vec![0, 2, 5],
+            ..Stats::default()
+        };
+        a.merge(&b);
+
+        assert_eq!(5, a.rays());
+        assert_eq!(vec![1, 3, 5], a.path_lengths);
+        assert_eq!(
+            "{\"primary_rays\":2,\"secondary_rays\":0,\"shadow_rays\":3,\"intersection_tests\":0,\
+             \"max_depth_terminations\":0,\"path_lengths\":[1,3,5]}",
+            a.json()
+        );
+    }
+}
@@ -1,10 +1,54 @@
+use std::cell::RefCell;
+
 use rand::distributions::Uniform;
-use rand::{thread_rng, Rng};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
 lazy_static! {
     static ref UNIT_UNIFORM: Uniform<f32> = { Uniform::new(0.0, 1.0) };
 }
 
+thread_local! {
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+
+    // Numbers for unit_random to return instead of its random sequence: see
+    // with_primary_samples.
+    static PRIMARY: RefCell<Option<(Vec<f32>, usize)>> = const { RefCell::new(None) };
+}
+
 pub fn unit_random() -> f32 {
-    thread_rng().sample(*UNIT_UNIFORM)
+    let primary = PRIMARY.with(|primary| {
+        primary.borrow_mut().as_mut().map(|(values, next)| {
+            if *next == values.len() {
+                values.push(random());
+            }
+            *next += 1;
+            values[*next - 1]
+        })
+    });
+    primary.unwrap_or_else(random)
+}
+
+fn random() -> f32 {
+    RNG.with(|rng| rng.borrow_mut().sample(*UNIT_UNIFORM))
+}
+
+// Runs f with unit_random returning the numbers in values, in order, so that whatever f
+// samples is a function of values (a point in primary sample space). Once values runs out,
+// fresh random numbers are added to it.
+pub(crate) fn with_primary_samples<T, F>(values: &mut Vec<f32>, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    PRIMARY.with(|primary| *primary.borrow_mut() = Some((std::mem::take(values), 0)));
+    let result = f();
+    if let Some((used, _)) = PRIMARY.with(|primary| primary.borrow_mut().take()) {
+        *values = used;
+    }
+    result
+}
+
+// Restarts this thread's random sequence from seed, so that a sample can be reproduced exactly.
+pub fn seed_unit_random(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
 }
@@ -1,6 +1,8 @@
+use std::f32;
+
 use crate::errors::*;
 use crate::unit_random::unit_random;
-use crate::vec3::Vec3;
+use crate::vec3::{orthonormal_basis, Vec3};
 
 pub fn range_check(val: f32, min: f32, max: f32) -> Result<()> {
     if val < min || val > max {
@@ -20,6 +22,35 @@ pub fn random_in_unit_sphere() -> Vec3 {
     }
 }
 
+// A unit vector, spread uniformly over the sphere as u0 and u1 range over [0, 1).
+pub(crate) fn uniform_sphere(u0: f32, u1: f32) -> Vec3 {
+    let y = 1.0 - 2.0 * u0;
+    let r = f32::sqrt(f32::max(0.0, 1.0 - y * y));
+    let phi = 2.0 * f32::consts::PI * u1;
+    Vec3::cartesian(r * f32::cos(phi), y, r * f32::sin(phi))
+}
+
+// A random unit vector in the hemisphere around normal (a unit vector), with density
+// cos(theta) / pi.
+pub(crate) fn random_cosine_direction(normal: &Vec3) -> Vec3 {
+    let (u, v) = orthonormal_basis(normal);
+    let phi = 2.0 * f32::consts::PI * unit_random();
+    let r2 = unit_random();
+    let r = f32::sqrt(r2);
+    r * f32::cos(phi) * u + r * f32::sin(phi) * v + f32::sqrt(1.0 - r2) * normal
+}
+
+// A random point in the unit disk, as (x, y).
+pub fn random_in_unit_disk() -> (f32, f32) {
+    loop {
+        let x = 2.0 * unit_random() - 1.0;
+        let y = 2.0 * unit_random() - 1.0;
+        if x * x + y * y < 1.0 {
+            return (x, y);
+        }
+    }
+}
+
 pub fn if_then<F, T>(cond: bool, f: F) -> Option<T>
 where
     F: FnOnce() -> Option<T>,
@@ -79,6 +79,19 @@ pub fn cross(lhs: &Vec3, rhs: &Vec3) -> Vec3 {
     )
 }
 
+// Two unit vectors perpendicular to n (a unit vector) and to each other.
+pub fn orthonormal_basis(n: &Vec3) -> (Vec3, Vec3) {
+    let a = if n.x().abs() > 0.9 {
+        Vec3::cartesian(0.0, 1.0, 0.0)
+    } else {
+        Vec3::cartesian(1.0, 0.0, 0.0)
+    };
+    let v = cross(n, &a);
+    let v = v / v.length();
+    let u = cross(&v, n);
+    (u, v)
+}
+
 fn parse_cylindrical(s: &str) -> Result<Vec3> {
     let (r, t, z) = split_three(s)?;
     Ok(Vec3::cylindrical(r, t, z))